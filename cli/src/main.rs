@@ -1,10 +1,13 @@
-use anyhow::Result;
-use standardfile::crypto::Crypto;
-use standardfile::remote::Client;
-use standardfile::{Credentials, Exported};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use standardfile::crypto::{self, Crypto};
+use standardfile::remote::{Client, MfaCode, RemoteError};
+use standardfile::{Credentials, Exported, Item};
+use standardfile_server::Server;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use uuid::Uuid;
 
 #[derive(StructOpt)]
 enum Command {
@@ -21,13 +24,47 @@ enum Command {
         identifier: String,
         #[structopt(long)]
         password: String,
+        /// Code of the second factor, if the account uses two-factor authentication.
+        #[structopt(long)]
+        mfa_code: Option<String>,
+    },
+    /// Print the versions the server kept of a note or restore one of them.
+    Revisions {
+        #[structopt(long)]
+        host: Option<String>,
+        #[structopt(long)]
+        identifier: String,
+        #[structopt(long)]
+        password: String,
+        /// Code of the second factor, if the account uses two-factor authentication.
+        #[structopt(long)]
+        mfa_code: Option<String>,
+        /// Uuid of the note.
+        uuid: Uuid,
+        /// Uuid of the revision to restore.
+        #[structopt(long)]
+        restore: Option<Uuid>,
+    },
+    /// Run a minimal sync server that keeps accounts and items in a local directory.
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:3000")]
+        address: String,
+        #[structopt(long, parse(from_os_str))]
+        data: PathBuf,
     },
 }
 
 fn decrypt(input: &Path, password: &str) -> Result<()> {
     let exported = Exported::from_str(&read_to_string(input)?)?;
-    let credentials = Credentials::from_exported(&exported, &password);
-    let crypto = Crypto::new(&credentials)?;
+    let credentials = Credentials::from_exported(&exported, password);
+    let mut crypto = Crypto::new(&credentials)?;
+
+    // Items keys must be known before the items they encrypt can be decrypted.
+    for item in exported.items.iter().filter(|item| item.content_type == "SN|ItemsKey") {
+        if let Item::ItemsKey(items_key) = item.decrypt(&crypto)? {
+            crypto.add_items_key(&items_key)?;
+        }
+    }
 
     for item in exported.items {
         let decrypted = crypto.decrypt(&item)?;
@@ -36,14 +73,81 @@ fn decrypt(input: &Path, password: &str) -> Result<()> {
     Ok(())
 }
 
-fn signin(host: Option<String>, identifier: &str, password: &str) -> Result<()> {
-    let credentials = Credentials::from_defaults(&identifier, &password);
+fn signin(host: Option<String>, identifier: &str, password: &str, mfa_code: Option<String>) -> Result<Client> {
+    let credentials = Credentials::from_defaults(identifier, password, crypto::DEFAULT_VERSION);
     let host = host.unwrap_or(String::from("https://sync.standardnotes.org"));
-    let _ = Client::new_sign_in(&host, &credentials)?;
+
+    match (Client::new_sign_in(&host, &credentials), mfa_code) {
+        (Err(err), Some(code)) => match err.downcast::<RemoteError>() {
+            Ok(RemoteError::MfaRequired(challenge)) => {
+                let mfa = MfaCode { key: challenge.mfa_key, code };
+                Client::new_sign_in_with_mfa(&host, &credentials, Some(&mfa))
+            }
+            Ok(err) => Err(err.into()),
+            Err(err) => Err(err),
+        },
+        (result, _) => result,
+    }
+}
+
+/// Print the revisions of the note `uuid` or, if `restore` is given, save that revision as the
+/// current version of the note.
+fn revisions(mut client: Client, uuid: &Uuid, restore: Option<Uuid>) -> Result<()> {
+    let mut crypto = Crypto::new(&client.credentials)?;
+
+    // Revisions may be encrypted with items keys that only come with a sync.
+    for item in client.sync(vec![])?.retrieved.iter().filter(|item| item.content_type == "SN|ItemsKey") {
+        if let Item::ItemsKey(items_key) = item.decrypt(&crypto)? {
+            crypto.add_items_key(&items_key)?;
+        }
+    }
+
+    if let Some(revision) = restore {
+        let mut note = match client.revision(uuid, &revision)?.decrypt(&crypto)? {
+            Item::Note(note) => note,
+            _ => return Err(anyhow!("Revision {} is not a note", revision)),
+        };
+
+        note.updated_at = Utc::now();
+        let result = client.sync(vec![Item::Note(note).encrypt(&crypto)?])?;
+
+        if !result.conflicts.is_empty() {
+            return Err(anyhow!("The server did not save the restored note"));
+        }
+
+        println!("Restored revision {} of {}", revision, uuid);
+        return Ok(());
+    }
+
+    for revision in client.revisions(uuid)? {
+        let decrypted = crypto.decrypt(&client.revision(uuid, &revision.uuid)?)?;
+        println!("{}: {}\n{}\n", revision.uuid, revision.created_at, decrypted);
+    }
+
     Ok(())
 }
 
-fn main() -> Result<()> {
+fn serve(address: &str, data: &Path) -> Result<()> {
+    let server = Server::start(address, Some(data))?;
+    println!("Serving on {}, storing data in {}", server.url(), data.display());
+    server.wait();
+    Ok(())
+}
+
+/// Suggest what to do about errors of the remote.
+fn hint(err: &anyhow::Error) -> Option<String> {
+    match err.downcast_ref::<RemoteError>()? {
+        RemoteError::InvalidCredentials(_) => Some("Check identifier and password.".to_string()),
+        RemoteError::MfaRequired(_) => Some("The account uses two-factor authentication, pass the code with --mfa-code.".to_string()),
+        RemoteError::ExpiredToken => Some("Sign in again.".to_string()),
+        RemoteError::RateLimited { retry_after: Some(delay) } => Some(format!("Try again in {} seconds.", delay.as_secs())),
+        RemoteError::RateLimited { retry_after: None } => Some("Try again later.".to_string()),
+        RemoteError::Network(_) => Some("Check the host address and your connection.".to_string()),
+        RemoteError::Server { .. } | RemoteError::InvalidJson(_) => None,
+    }
+}
+
+fn run() -> Result<()> {
     match Command::from_args() {
         Command::Decrypt { input, password } => {
             decrypt(&input, &password)?;
@@ -52,10 +156,36 @@ fn main() -> Result<()> {
             host,
             identifier,
             password,
+            mfa_code,
         } => {
-            signin(host, &identifier, &password)?;
+            signin(host, &identifier, &password, mfa_code)?;
+        }
+        Command::Revisions {
+            host,
+            identifier,
+            password,
+            mfa_code,
+            uuid,
+            restore,
+        } => {
+            revisions(signin(host, &identifier, &password, mfa_code)?, &uuid, restore)?;
+        }
+        Command::Serve { address, data } => {
+            serve(&address, &data)?;
         }
     };
 
     Ok(())
 }
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+
+        if let Some(hint) = hint(&err) {
+            eprintln!("{}", hint);
+        }
+
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,446 @@
+#![forbid(unsafe_code)]
+// `is_some_and` is too recent for the toolchains we support.
+#![allow(clippy::unnecessary_map_or)]
+
+use anyhow::{anyhow, Result};
+use state::{Reply, State};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Method, Request, Response};
+
+mod state;
+
+/// Name of the file in the data directory holding accounts and items.
+static STATE_FILE: &str = "server.json";
+
+/// Minimal Standard Notes sync server, good enough to sync with `remote::Client`. Requests are
+/// handled one after another on a background thread until the server is dropped.
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    state: Arc<Mutex<State>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Serve on `address`. Accounts and items are kept in `directory` if given and in memory
+    /// otherwise.
+    pub fn start(address: &str, directory: Option<&Path>) -> Result<Server> {
+        let path = match directory {
+            Some(directory) => {
+                fs::create_dir_all(directory)?;
+                Some(directory.join(STATE_FILE))
+            }
+            None => None,
+        };
+
+        let state = match &path {
+            Some(path) if path.exists() => State::load(path)?,
+            _ => State::default(),
+        };
+
+        let http = tiny_http::Server::http(address)
+            .map_err(|err| anyhow!("Could not listen on {}: {}", address, err))?;
+
+        let http = Arc::new(http);
+        let state = Arc::new(Mutex::new(state));
+        let thread_http = http.clone();
+        let thread_state = state.clone();
+        let thread = thread::spawn(move || serve(&thread_http, &thread_state, path));
+
+        Ok(Self {
+            http,
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serve on a free port of the loopback interface and keep everything in memory, e.g. to
+    /// run tests against.
+    pub fn start_local() -> Result<Server> {
+        Server::start("127.0.0.1:0", None)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.http.server_addr()
+    }
+
+    /// Address to pass to `remote::Client`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address())
+    }
+
+    /// Let the access tokens of all sessions expire, so clients have to refresh their sessions.
+    pub fn expire_access_tokens(&self) {
+        self.state.lock().unwrap().expire_access_tokens();
+    }
+
+    /// Block until the server stops.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.http.unblock();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(http: &tiny_http::Server, state: &Mutex<State>, path: Option<PathBuf>) {
+    for mut request in http.incoming_requests() {
+        let mut state = state.lock().unwrap();
+        let (status, body) = handle(&mut state, &mut request);
+
+        let (status, body) = match &path {
+            Some(path) if *request.method() != Method::Get && status == 200 => match state.save(path) {
+                Ok(_) => (status, body),
+                Err(err) => (500, serde_json::json!({ "error": { "message": format!("Could not save: {}", err) } })),
+            },
+            _ => (status, body),
+        };
+
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+
+        // The client is gone if this fails, nothing we can do about it.
+        let _ = request.respond(response);
+    }
+}
+
+fn handle(state: &mut State, request: &mut Request) -> Reply {
+    let mut body = String::new();
+
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return (400, serde_json::json!({ "error": { "message": "Could not read request" } }));
+    }
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    let query = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(|token| token.to_string());
+
+    state.handle(request.method().as_str(), path, &query, token.as_deref(), &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use standardfile::{crypto::Crypto, remote::{Client, ConflictType, MfaCode, RemoteError}, Credentials, Envelope, Item, Note};
+    use chrono::{Duration, Utc};
+    use data_encoding::BASE64;
+    use uuid::Uuid;
+
+    fn credentials(identifier: &str) -> Credentials {
+        Credentials {
+            identifier: identifier.to_string(),
+            password: "secret".to_string(),
+            cost: 110000,
+            nonce: "nonce".to_string(),
+            version: "003".to_string(),
+        }
+    }
+
+    fn note(title: &str) -> Note {
+        Note {
+            title: title.to_string(),
+            text: String::new(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            uuid: Uuid::new_v4(),
+            flags: Default::default(),
+            extra: Default::default(),
+        }
+    }
+
+    fn encrypt(crypto: &Crypto, note: Note) -> Envelope {
+        Item::Note(note).encrypt(crypto).unwrap()
+    }
+
+    #[test]
+    fn test_register_and_sign_in() {
+        let server = Server::start_local().unwrap();
+        Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+
+        let client = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+        assert_eq!(client.credentials.nonce, "nonce");
+
+        let mut wrong = credentials("foo@bar.com");
+        wrong.password = "wrong".to_string();
+
+        let err = Client::new_sign_in(&server.url(), &wrong).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::InvalidCredentials(_))));
+    }
+
+    #[test]
+    fn test_sync() {
+        let server = Server::start_local().unwrap();
+        let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let mut second = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+
+        let crypto = Crypto::new(&first.credentials).unwrap();
+        let item = encrypt(&crypto, note("first"));
+        let result = first.sync(vec![item.clone()]).unwrap();
+        assert_eq!(result.saved.len(), 1);
+        assert!(result.retrieved.is_empty());
+
+        let result = second.sync(vec![]).unwrap();
+        assert_eq!(result.retrieved.len(), 1);
+        assert_eq!(result.retrieved[0].uuid, item.uuid);
+
+        // Nothing changed since the last sync.
+        assert!(second.sync(vec![]).unwrap().retrieved.is_empty());
+        assert!(first.sync(vec![]).unwrap().retrieved.is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_sync_async() {
+        use standardfile::remote::nonblocking::{self, Client};
+
+        let server = Server::start_local().unwrap();
+        let mut runtime = nonblocking::runtime().unwrap();
+
+        runtime.block_on(async {
+            let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).await.unwrap();
+            let mut second = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).await.unwrap();
+
+            let crypto = Crypto::new(&first.credentials).unwrap();
+            let item = encrypt(&crypto, note("first"));
+            let result = first.sync(vec![item.clone()]).await.unwrap();
+            assert_eq!(result.saved.len(), 1);
+
+            let result = second.sync(vec![]).await.unwrap();
+            assert_eq!(result.retrieved.len(), 1);
+            assert_eq!(result.retrieved[0].uuid, item.uuid);
+            assert!(second.sync(vec![]).await.unwrap().retrieved.is_empty());
+
+            let mut wrong = credentials("foo@bar.com");
+            wrong.password = "wrong".to_string();
+
+            let err = Client::new_sign_in(&server.url(), &wrong).await.err().unwrap();
+            assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::InvalidCredentials(_))));
+        });
+    }
+
+    #[test]
+    fn test_sync_pages() {
+        let server = Server::start_local().unwrap();
+        let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let mut second = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+
+        let crypto = Crypto::new(&first.credentials).unwrap();
+        let items = (0..320).map(|i| encrypt(&crypto, note(&i.to_string()))).collect::<Vec<_>>();
+        first.sync(items).unwrap();
+
+        let mut pages = Vec::new();
+        let result = second.sync_with_progress(vec![], |count| pages.push(count)).unwrap();
+        assert_eq!(result.retrieved.len(), 320);
+        assert_eq!(pages, vec![150, 300, 320]);
+    }
+
+    #[test]
+    fn test_sync_conflict() {
+        let server = Server::start_local().unwrap();
+        let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let mut second = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+
+        let mut older = note("older");
+        older.updated_at = Utc::now() - Duration::minutes(1);
+        let mut newer = note("newer");
+        newer.uuid = older.uuid;
+
+        let crypto = Crypto::new(&first.credentials).unwrap();
+        first.sync(vec![encrypt(&crypto, newer)]).unwrap();
+
+        let result = second.sync(vec![encrypt(&crypto, older)]).unwrap();
+        assert!(result.saved.is_empty());
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].kind, ConflictType::SyncConflict);
+        assert!(result.conflicts[0].server_item.is_some());
+    }
+
+    #[test]
+    fn test_uuid_conflict() {
+        let server = Server::start_local().unwrap();
+        let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let mut second = Client::new_register(&server.url(), credentials("bar@foo.com")).unwrap();
+
+        let item = note("note");
+        let uuid = item.uuid;
+        first.sync(vec![encrypt(&Crypto::new(&first.credentials).unwrap(), item)]).unwrap();
+
+        let mut other = note("other");
+        other.uuid = uuid;
+
+        let result = second.sync(vec![encrypt(&Crypto::new(&second.credentials).unwrap(), other)]).unwrap();
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].kind, ConflictType::UuidConflict);
+    }
+
+    #[test]
+    fn test_revisions() {
+        let server = Server::start_local().unwrap();
+        let mut client = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let crypto = Crypto::new(&client.credentials).unwrap();
+
+        let mut first = note("first");
+        first.updated_at = Utc::now() - Duration::minutes(1);
+        let mut second = note("second");
+        second.uuid = first.uuid;
+
+        client.sync(vec![encrypt(&crypto, first)]).unwrap();
+        let uuid = second.uuid;
+        client.sync(vec![encrypt(&crypto, second)]).unwrap();
+
+        let revisions = client.revisions(&uuid).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert!(revisions.iter().all(|revision| revision.item_uuid == uuid));
+
+        let envelope = client.revision(&uuid, &revisions[1].uuid).unwrap();
+        match envelope.decrypt(&crypto).unwrap() {
+            Item::Note(note) => assert_eq!(note.title, "first"),
+            _ => panic!("Expected a note"),
+        }
+
+        // Revisions of other accounts are not visible.
+        let mut other = Client::new_register(&server.url(), credentials("bar@foo.com")).unwrap();
+        assert!(other.revisions(&uuid).unwrap().is_empty());
+        assert!(other.revision(&uuid, &revisions[0].uuid).is_err());
+    }
+
+    #[test]
+    fn test_change_password() {
+        let server = Server::start_local().unwrap();
+        let client = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+
+        // A wrong current password must not be taken for an expired session.
+        let mut wrong = client.credentials.clone();
+        wrong.password = "wrong".to_string();
+        let mut impostor = Client::from_session(&server.url(), &wrong, client.session().clone());
+
+        let err = impostor.change_password(&credentials("foo@bar.com")).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::InvalidCredentials(_))));
+
+        // The session has been refreshed on the way and is still valid.
+        let mut client = Client::from_session(&server.url(), &client.credentials, impostor.session().clone());
+        client.sync(vec![]).unwrap();
+
+        let mut changed = credentials("foo@bar.com");
+        changed.password = "new secret".to_string();
+        let session = client.session().clone();
+        client.change_password(&changed).unwrap();
+        assert!(*client.session() != session);
+        client.sync(vec![]).unwrap();
+
+        Client::new_sign_in(&server.url(), &changed).unwrap();
+        assert!(Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).is_err());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let server = Server::start_local().unwrap();
+        let mut first = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        let mut second = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+        Client::new_register(&server.url(), credentials("bar@foo.com")).unwrap();
+
+        let sessions = first.sessions().unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        let current = sessions.iter().find(|session| session.current).unwrap().uuid;
+        let other = sessions.iter().find(|session| !session.current).unwrap().uuid;
+
+        // Sessions keep their identity when they are refreshed.
+        server.expire_access_tokens();
+        let uuids = second.sessions().unwrap().iter().map(|session| session.uuid).collect::<Vec<_>>();
+        assert_eq!(uuids, vec![current, other]);
+
+        let err = first.revoke_session(&current).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::Server { .. })));
+
+        first.revoke_session(&other).unwrap();
+        assert_eq!(first.sessions().unwrap().len(), 1);
+
+        let err = second.sync(vec![]).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::ExpiredToken)));
+    }
+
+    #[test]
+    fn test_mfa() {
+        let server = Server::start_local().unwrap();
+        let mut client = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+
+        // Clients enable two-factor authentication by syncing an unencrypted item.
+        let mfa = Envelope {
+            uuid: Uuid::new_v4(),
+            content: Some(format!("000{}", BASE64.encode(br#"{"secret":"123456"}"#))),
+            content_type: "SF|MFA".to_string(),
+            enc_item_key: None,
+            items_key_id: None,
+            auth_hash: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            deleted: Some(false),
+        };
+
+        client.sync(vec![mfa.clone()]).unwrap();
+
+        let err = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).err().unwrap();
+
+        let challenge = match err.downcast::<RemoteError>() {
+            Ok(RemoteError::MfaRequired(challenge)) => challenge,
+            other => panic!("Expected an MFA challenge, got {:?}", other),
+        };
+
+        assert_eq!(challenge.mfa_key, format!("mfa_{}", mfa.uuid));
+
+        let wrong = MfaCode { key: challenge.mfa_key.clone(), code: "654321".to_string() };
+        let err = Client::new_sign_in_with_mfa(&server.url(), &credentials("foo@bar.com"), Some(&wrong)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::MfaRequired(_))));
+
+        let code = MfaCode { key: challenge.mfa_key, code: "123456".to_string() };
+        Client::new_sign_in_with_mfa(&server.url(), &credentials("foo@bar.com"), Some(&code)).unwrap();
+
+        // Removing the item disables two-factor authentication again.
+        let mut deleted = mfa;
+        deleted.deleted = Some(true);
+        deleted.updated_at = Utc::now();
+        client.sync(vec![deleted]).unwrap();
+        Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+    }
+
+    #[test]
+    fn test_persistence() {
+        let directory = std::env::temp_dir().join(format!("standardfile-server-{}", Uuid::new_v4()));
+        let server = Server::start("127.0.0.1:0", Some(&directory)).unwrap();
+        let mut client = Client::new_register(&server.url(), credentials("foo@bar.com")).unwrap();
+        client.sync(vec![encrypt(&Crypto::new(&client.credentials).unwrap(), note("note"))]).unwrap();
+        drop(server);
+
+        let server = Server::start("127.0.0.1:0", Some(&directory)).unwrap();
+        let mut client = Client::new_sign_in(&server.url(), &credentials("foo@bar.com")).unwrap();
+        assert_eq!(client.sync(vec![]).unwrap().retrieved.len(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
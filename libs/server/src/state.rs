@@ -0,0 +1,576 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use data_encoding::BASE64;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::{json, Value};
+use standardfile::Envelope;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Lifetime of access tokens in milliseconds.
+const ACCESS_LIFETIME: i64 = 24 * 60 * 60 * 1000;
+
+/// Lifetime of refresh tokens in milliseconds.
+const REFRESH_LIFETIME: i64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Number of items retrieved per sync request unless the client asks for fewer.
+const PAGE_SIZE: usize = 150;
+
+/// API revision reported for sessions.
+static API_VERSION: &str = "20200115";
+
+/// Status code of requests made with an expired access token.
+const EXPIRED_ACCESS_TOKEN: u16 = 498;
+
+/// Content type of the item enabling two-factor authentication.
+static MFA_CONTENT_TYPE: &str = "SF|MFA";
+
+/// Status code and JSON body of a response.
+pub type Reply = (u16, Value);
+
+fn error(status: u16, message: &str) -> Reply {
+    (status, json!({ "error": { "message": message } }))
+}
+
+fn parse<T: DeserializeOwned>(body: &str) -> Result<T, Reply> {
+    serde_json::from_str(body).map_err(|err| error(400, &format!("Invalid request: {}", err)))
+}
+
+fn make_token() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+#[derive(Serialize, Deserialize)]
+struct User {
+    uuid: Uuid,
+    email: String,
+    /// Server password derived by the client, never the password itself.
+    password: String,
+    pw_cost: u32,
+    pw_nonce: String,
+    version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    /// Identifies the session across refreshes.
+    #[serde(default = "Uuid::new_v4")]
+    uuid: Uuid,
+    #[serde(default = "Utc::now")]
+    created_at: DateTime<Utc>,
+    /// Time at which the current tokens have been handed out.
+    #[serde(default = "Utc::now")]
+    updated_at: DateTime<Utc>,
+    email: String,
+    access_expiration: i64,
+    refresh_token: String,
+    refresh_expiration: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredItem {
+    owner: Uuid,
+    /// Position in the change log, sync and cursor tokens refer to these.
+    seq: u64,
+    item: Envelope,
+}
+
+/// Earlier version of an item, kept whenever the item is saved.
+#[derive(Serialize, Deserialize)]
+struct StoredRevision {
+    uuid: Uuid,
+    created_at: DateTime<Utc>,
+    item: Envelope,
+}
+
+impl StoredRevision {
+    /// Revision as listed, without content.
+    fn summary(&self) -> Value {
+        json!({
+            "uuid": self.uuid,
+            "item_uuid": self.item.uuid,
+            "content_type": self.item.content_type,
+            "created_at": self.created_at,
+            "updated_at": self.item.updated_at,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RegistrationRequest {
+    #[serde(default)]
+    api: Option<String>,
+    email: String,
+    password: String,
+    #[serde(default)]
+    pw_cost: u32,
+    pw_nonce: String,
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct SignInRequest {
+    #[serde(default)]
+    api: Option<String>,
+    email: String,
+    password: String,
+    /// Answer to a two-factor challenge, keyed by the challenge's `mfa_key`.
+    #[serde(flatten)]
+    mfa: HashMap<String, Value>,
+}
+
+/// Unencrypted content of the item enabling two-factor authentication.
+#[derive(Deserialize)]
+struct MfaContent {
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    #[serde(default)]
+    api: Option<String>,
+    current_password: String,
+    new_password: String,
+    #[serde(default)]
+    pw_cost: u32,
+    pw_nonce: String,
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    access_token: String,
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionRequest {
+    uuid: Uuid,
+}
+
+#[derive(Deserialize)]
+struct SyncRequest {
+    #[serde(default)]
+    items: Vec<Envelope>,
+    #[serde(default)]
+    sync_token: Option<String>,
+    #[serde(default)]
+    cursor_token: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Accounts, sessions and items of the server.
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    users: HashMap<String, User>,
+    /// Sessions by access token.
+    sessions: HashMap<String, Session>,
+    items: HashMap<Uuid, StoredItem>,
+    /// Revisions by item, oldest first.
+    #[serde(default)]
+    revisions: HashMap<Uuid, Vec<StoredRevision>>,
+    /// Sequence number of the latest change.
+    counter: u64,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<State> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Write the state to `path`. A temporary file is renamed so an interrupted write does not
+    /// lose the previous state.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string(self)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Answer a request. `token` is the bearer token of the request, if any.
+    pub fn handle(&mut self, method: &str, path: &str, query: &HashMap<String, String>, token: Option<&str>, body: &str) -> Reply {
+        let result = match (method, path) {
+            ("POST", "/auth") => self.register(body),
+            ("GET", "/auth/params") => self.params(query),
+            ("POST", "/auth/sign_in") => self.sign_in(body),
+            ("POST", "/auth/change_pw") => self.change_password(token, body),
+            ("POST", "/auth/sign_out") => self.sign_out(token),
+            ("POST", "/session/refresh") => self.refresh(body),
+            ("GET", "/sessions") => self.sessions(token),
+            ("DELETE", "/session") => self.revoke_session(token, body),
+            ("POST", "/items/sync") => self.sync(token, body),
+            ("GET", path) if path.starts_with("/items/") => self.revisions(token, &path["/items/".len()..]),
+            _ => Err(error(404, "Not found")),
+        };
+
+        match result {
+            Ok(value) => (200, value),
+            Err(reply) => reply,
+        }
+    }
+
+    /// Start a session for `email`. Clients that do not send an API version predate sessions
+    /// and get a plain token instead.
+    fn start_session(&mut self, email: &str, legacy: bool) -> Value {
+        self.issue_tokens(email, legacy, Uuid::new_v4(), Utc::now())
+    }
+
+    /// Hand out new tokens for the session `uuid` of `email` started at `created_at`.
+    fn issue_tokens(&mut self, email: &str, legacy: bool, uuid: Uuid, created_at: DateTime<Utc>) -> Value {
+        let now = Utc::now().timestamp_millis();
+        let access_token = make_token();
+
+        let session = Session {
+            uuid,
+            created_at,
+            updated_at: Utc::now(),
+            email: email.to_string(),
+            access_expiration: now + ACCESS_LIFETIME,
+            refresh_token: make_token(),
+            refresh_expiration: now + REFRESH_LIFETIME,
+        };
+
+        let user = &self.users[email];
+        let user = json!({ "uuid": user.uuid, "email": user.email });
+
+        let value = if legacy {
+            json!({ "token": access_token, "user": user })
+        } else {
+            json!({
+                "session": {
+                    "access_token": access_token,
+                    "refresh_token": session.refresh_token,
+                    "access_expiration": session.access_expiration,
+                    "refresh_expiration": session.refresh_expiration,
+                },
+                "user": user,
+            })
+        };
+
+        self.sessions.insert(access_token, session);
+        value
+    }
+
+    pub fn expire_access_tokens(&mut self) {
+        let now = Utc::now().timestamp_millis();
+
+        for session in self.sessions.values_mut() {
+            session.access_expiration = now;
+        }
+    }
+
+    /// Email of the account the access token belongs to.
+    fn authenticate(&self, token: Option<&str>) -> Result<String, Reply> {
+        let session = token
+            .and_then(|token| self.sessions.get(token))
+            .ok_or_else(|| error(401, "Invalid login credentials"))?;
+
+        if session.access_expiration <= Utc::now().timestamp_millis() {
+            return Err(error(EXPIRED_ACCESS_TOKEN, "The provided access token has expired."));
+        }
+
+        Ok(session.email.clone())
+    }
+
+    fn register(&mut self, body: &str) -> Result<Value, Reply> {
+        let request = parse::<RegistrationRequest>(body)?;
+
+        if self.users.contains_key(&request.email) {
+            return Err(error(400, "This email is already registered."));
+        }
+
+        let user = User {
+            uuid: Uuid::new_v4(),
+            email: request.email.clone(),
+            password: request.password,
+            pw_cost: request.pw_cost,
+            pw_nonce: request.pw_nonce,
+            version: request.version,
+        };
+
+        self.users.insert(request.email.clone(), user);
+        Ok(self.start_session(&request.email, request.api.is_none()))
+    }
+
+    /// Require a code if the account has two-factor authentication enabled, i.e. an `SF|MFA`
+    /// item with unencrypted "000" content. Unlike real servers we do not compute TOTP codes,
+    /// the secret itself is the code.
+    fn check_mfa(&self, email: &str, code: impl Fn(&str) -> Option<String>) -> Result<(), Reply> {
+        let owner = self.users[email].uuid;
+
+        let mfa = self.items.values().find(|stored| {
+            stored.owner == owner && stored.item.content_type == MFA_CONTENT_TYPE && stored.item.deleted != Some(true)
+        });
+
+        let (uuid, secret) = match mfa {
+            Some(stored) => {
+                let content = stored.item.content.as_deref().and_then(|content| content.strip_prefix("000"));
+                let content = content.and_then(|content| BASE64.decode(content.as_bytes()).ok());
+                let content = content.and_then(|content| serde_json::from_slice::<MfaContent>(&content).ok());
+
+                match content {
+                    Some(content) => (stored.item.uuid, content.secret),
+                    None => return Err(error(500, "Invalid two-factor authentication item.")),
+                }
+            }
+            None => return Ok(()),
+        };
+
+        let mfa_key = format!("mfa_{}", uuid);
+
+        let message = match code(&mfa_key) {
+            Some(code) if code == secret => return Ok(()),
+            Some(_) => "The two-factor authentication code you entered is incorrect.",
+            None => "Please enter your two-factor authentication code.",
+        };
+
+        Err((401, json!({
+            "error": {
+                "tag": "mfa-required",
+                "message": message,
+                "payload": { "mfa_key": mfa_key },
+            }
+        })))
+    }
+
+    fn params(&self, query: &HashMap<String, String>) -> Result<Value, Reply> {
+        let user = query
+            .get("email")
+            .and_then(|email| self.users.get(email))
+            .ok_or_else(|| error(404, "No account with this email."))?;
+
+        self.check_mfa(&user.email, |key| query.get(key).cloned())?;
+
+        Ok(json!({
+            "identifier": user.email,
+            "pw_cost": user.pw_cost,
+            "pw_nonce": user.pw_nonce,
+            "version": user.version,
+        }))
+    }
+
+    fn sign_in(&mut self, body: &str) -> Result<Value, Reply> {
+        let request = parse::<SignInRequest>(body)?;
+
+        if self.users.contains_key(&request.email) {
+            self.check_mfa(&request.email, |key| request.mfa.get(key).and_then(Value::as_str).map(str::to_string))?;
+        }
+
+        match self.users.get(&request.email) {
+            Some(user) if user.password == request.password => Ok(self.start_session(&request.email, request.api.is_none())),
+            _ => Err(error(401, "Invalid email or password.")),
+        }
+    }
+
+    /// Replace the password and encryption parameters. All sessions of the account end, the
+    /// client gets a new one.
+    fn change_password(&mut self, token: Option<&str>, body: &str) -> Result<Value, Reply> {
+        let email = self.authenticate(token)?;
+        let request = parse::<ChangePasswordRequest>(body)?;
+        let user = self.users.get_mut(&email).unwrap();
+
+        if user.password != request.current_password {
+            return Err(error(401, "The current password you entered is incorrect."));
+        }
+
+        user.password = request.new_password;
+        user.pw_cost = request.pw_cost;
+        user.pw_nonce = request.pw_nonce;
+        user.version = request.version;
+
+        self.sessions.retain(|_, session| session.email != email);
+        Ok(self.start_session(&email, request.api.is_none()))
+    }
+
+    fn sign_out(&mut self, token: Option<&str>) -> Result<Value, Reply> {
+        self.authenticate(token)?;
+        self.sessions.remove(token.unwrap_or_default());
+        Ok(json!({}))
+    }
+
+    fn refresh(&mut self, body: &str) -> Result<Value, Reply> {
+        let request = parse::<RefreshRequest>(body)?;
+
+        let session = match self.sessions.remove(&request.access_token) {
+            Some(session) if session.refresh_token == request.refresh_token => session,
+            Some(session) => {
+                self.sessions.insert(request.access_token, session);
+                return Err(error(400, "The provided refresh token is invalid."));
+            }
+            None => return Err(error(400, "The provided refresh token is invalid.")),
+        };
+
+        if session.refresh_expiration <= Utc::now().timestamp_millis() {
+            return Err(error(400, "The provided refresh token has expired."));
+        }
+
+        Ok(self.issue_tokens(&session.email, false, session.uuid, session.created_at))
+    }
+
+    /// List the sessions of the user, marking the one making the request.
+    fn sessions(&self, token: Option<&str>) -> Result<Value, Reply> {
+        let email = self.authenticate(token)?;
+
+        let mut sessions = self.sessions
+            .iter()
+            .filter(|(_, session)| session.email == email)
+            .collect::<Vec<_>>();
+
+        sessions.sort_by_key(|(_, session)| session.created_at);
+
+        let sessions = sessions
+            .into_iter()
+            .map(|(access_token, session)| json!({
+                "uuid": session.uuid,
+                "api_version": API_VERSION,
+                "created_at": session.created_at,
+                "updated_at": session.updated_at,
+                "device_info": "",
+                "current": Some(access_token.as_str()) == token,
+            }))
+            .collect::<Vec<_>>();
+
+        Ok(json!(sessions))
+    }
+
+    /// End another session of the user.
+    fn revoke_session(&mut self, token: Option<&str>, body: &str) -> Result<Value, Reply> {
+        let email = self.authenticate(token)?;
+        let request = parse::<RevokeSessionRequest>(body)?;
+
+        let access_token = self.sessions
+            .iter()
+            .find(|(_, session)| session.uuid == request.uuid && session.email == email)
+            .map(|(access_token, _)| access_token.clone())
+            .ok_or_else(|| error(400, "No session exists with the provided identifier."))?;
+
+        if Some(access_token.as_str()) == token {
+            return Err(error(400, "You can not delete your current session."));
+        }
+
+        self.sessions.remove(&access_token);
+        Ok(json!({}))
+    }
+
+    /// Save the uploaded items and return the items changed since the sync token, at most one
+    /// page at a time. Items older than the version on the server are not saved but reported
+    /// as conflicts, deletions always win.
+    fn sync(&mut self, token: Option<&str>, body: &str) -> Result<Value, Reply> {
+        let email = self.authenticate(token)?;
+        let request = parse::<SyncRequest>(body)?;
+        let owner = self.users[&email].uuid;
+
+        // Cursors continue a sync that did not fit into one page and take precedence.
+        let since = request
+            .cursor_token
+            .as_ref()
+            .or(request.sync_token.as_ref())
+            .and_then(|token| token.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let mut saved = Vec::new();
+        let mut conflicts = Vec::new();
+
+        for mut item in request.items {
+            match self.items.get(&item.uuid) {
+                Some(stored) if stored.owner != owner => {
+                    conflicts.push(json!({ "type": "uuid_conflict", "unsaved_item": item }));
+                }
+                Some(stored) if item.deleted != Some(true) && item.updated_at < stored.item.updated_at => {
+                    conflicts.push(json!({
+                        "type": "sync_conflict",
+                        "server_item": stored.item,
+                        "unsaved_item": item,
+                    }));
+                }
+                _ => {
+                    if item.deleted == Some(true) {
+                        item.content = None;
+                        item.enc_item_key = None;
+                        item.auth_hash = None;
+                        self.revisions.remove(&item.uuid);
+                    } else {
+                        let revision = StoredRevision { uuid: Uuid::new_v4(), created_at: Utc::now(), item: item.clone() };
+                        self.revisions.entry(item.uuid).or_default().push(revision);
+                    }
+
+                    self.counter += 1;
+                    saved.push(item.clone());
+                    self.items.insert(item.uuid, StoredItem { owner, seq: self.counter, item });
+                }
+            }
+        }
+
+        let skip = saved.iter().map(|item| item.uuid)
+            .chain(conflicts.iter().filter_map(|conflict| conflict["server_item"]["uuid"].as_str()?.parse().ok()))
+            .collect::<HashSet<Uuid>>();
+
+        let mut changed = self.items
+            .values()
+            .filter(|stored| stored.owner == owner && stored.seq > since && !skip.contains(&stored.item.uuid))
+            .collect::<Vec<_>>();
+
+        changed.sort_by_key(|stored| stored.seq);
+
+        let limit = request.limit.unwrap_or(PAGE_SIZE).clamp(1, PAGE_SIZE);
+
+        // A client that stops before retrieving all pages must continue from the last page it
+        // got, so the sync token only moves to the latest change with the last page.
+        let (sync_token, cursor_token) = if changed.len() > limit {
+            changed.truncate(limit);
+            let last = changed.last().map_or(since, |stored| stored.seq);
+            (last, Some(last.to_string()))
+        } else {
+            (self.counter, None)
+        };
+
+        let retrieved = changed.into_iter().map(|stored| &stored.item).collect::<Vec<_>>();
+
+        Ok(json!({
+            "retrieved_items": retrieved,
+            "saved_items": saved,
+            "conflicts": conflicts,
+            "sync_token": sync_token.to_string(),
+            "cursor_token": cursor_token,
+        }))
+    }
+
+    /// Answer `{item}/revisions` with the revisions of an item, newest first, and
+    /// `{item}/revisions/{revision}` with a single revision including its content.
+    fn revisions(&self, token: Option<&str>, path: &str) -> Result<Value, Reply> {
+        let email = self.authenticate(token)?;
+        let owner = self.users[&email].uuid;
+        let parts = path.split('/').collect::<Vec<_>>();
+
+        let (item, revision) = match parts.as_slice() {
+            [item, "revisions"] => (item.parse::<Uuid>().ok(), None),
+            [item, "revisions", revision] => (item.parse::<Uuid>().ok(), Some(revision.parse::<Uuid>().ok())),
+            _ => return Err(error(404, "Not found")),
+        };
+
+        let revisions = item
+            .filter(|uuid| self.items.get(uuid).map_or(false, |stored| stored.owner == owner))
+            .and_then(|uuid| self.revisions.get(&uuid))
+            .map_or(&[][..], |revisions| revisions.as_slice());
+
+        match revision {
+            None => Ok(Value::Array(revisions.iter().rev().map(StoredRevision::summary).collect())),
+            Some(uuid) => {
+                let revision = revisions
+                    .iter()
+                    .find(|revision| Some(revision.uuid) == uuid)
+                    .ok_or_else(|| error(404, "Revision not found."))?;
+
+                let mut value = revision.summary();
+                value["content"] = json!(revision.item.content);
+                value["enc_item_key"] = json!(revision.item.enc_item_key);
+                value["items_key_id"] = json!(revision.item.items_key_id);
+                value["auth_hash"] = json!(revision.item.auth_hash);
+                Ok(value)
+            }
+        }
+    }
+}
@@ -1,38 +1,118 @@
-use crate::{Envelope, Credentials, CryptoError};
+use crate::{Envelope, Credentials, CryptoError, ItemsKey};
 use aes::Aes256;
 use block_modes::block_padding::Pkcs7;
 use block_modes::{BlockMode, Cbc};
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
 use data_encoding::{BASE64, HEXLOWER};
 use rand::prelude::*;
 use ring::{digest, hmac, error};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::str;
 use std::num::NonZeroU32;
 use uuid::Uuid;
 
 pub type Key = [u8; 768 / 8 / 3];
 
+/// Protocol version used for new accounts and password changes.
+pub static DEFAULT_VERSION: &str = "004";
+
 pub struct Crypto {
+    version: String,
+    identifier: String,
+    nonce: String,
     pw: Key,
     mk: Key,
     ak: Key,
+    items_keys: HashMap<Uuid, Key>,
+    default_items_key: Option<Uuid>,
 }
 
 pub struct Encrypted {
     pub content: String,
     pub enc_item_key: String,
+    pub items_key_id: Option<Uuid>,
+}
+
+/// Additional data authenticated alongside 004 ciphertexts.
+#[derive(Serialize, Deserialize)]
+struct AuthenticatedData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kp: Option<serde_json::Value>,
+    u: Uuid,
+    v: String,
 }
 
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
 
-fn decrypt(s: &str, ek: &Key, ak: &Key, check_uuid: &Uuid) -> Result<String, CryptoError> {
+/// Return the protocol version prefix of an encrypted string.
+fn version_of(s: &str) -> &str {
+    s.get(..3).unwrap_or("")
+}
+
+/// Return true if the scheme can only be used to decrypt existing items.
+pub fn is_read_only(version: &str) -> bool {
+    version == "001" || version == "002"
+}
+
+/// Return the PBKDF2 cost used for new credentials of `version`. 004 derives its keys with fixed
+/// Argon2 parameters and has no cost.
+pub fn default_cost(version: &str) -> u32 {
+    match version {
+        "004" => 0,
+        _ => 110000,
+    }
+}
+
+/// Split a hex-encoded 512 bit item key into encryption and authentication key.
+fn split_item_key(item_key: &str) -> Result<(Key, Key), CryptoError> {
+    if item_key.len() != 128 {
+        return Err(CryptoError::Malformed);
+    }
+
+    let mut item_ek: Key = [0; 32];
+    let mut item_ak: Key = [0; 32];
+
+    HEXLOWER
+        .decode_mut(&item_key.as_bytes()[..64], &mut item_ek)
+        .map_err(|partial| partial.error)?;
+    HEXLOWER
+        .decode_mut(&item_key.as_bytes()[64..], &mut item_ak)
+        .map_err(|partial| partial.error)?;
+
+    Ok((item_ek, item_ak))
+}
+
+/// Decrypt 001 strings which consist of the version followed by the Base64-encoded ciphertext.
+/// The IV is all zeros and authentication happens separately, if at all.
+fn decrypt_001(s: &str, ek: &Key) -> Result<String, CryptoError> {
+    if version_of(s) != "001" {
+        return Err(CryptoError::UnsupportedScheme(version_of(s).to_string()));
+    }
+
+    let cipher = Aes256Cbc::new_var(ek, &[0u8; 16])?;
+    let ciphertext_bytes = BASE64.decode(&s.as_bytes()[3..])?;
+    let decrypted = cipher.decrypt_vec(ciphertext_bytes.as_ref())?;
+    Ok(str::from_utf8(decrypted.as_ref())?.to_string())
+}
+
+/// Decrypt 002 and 003 strings, which only differ in how the root key is derived.
+fn decrypt_003(s: &str, ek: &Key, ak: &Key, check_uuid: &Uuid) -> Result<String, CryptoError> {
     let s: Vec<&str> = s.split(':').collect();
+
+    if s.len() != 5 {
+        return Err(CryptoError::Malformed);
+    }
+
     let version = s[0];
     let auth_hash = s[1];
     let uuid = Uuid::parse_str(s[2])?;
     let iv = s[3];
     let ciphertext = s[4];
 
-    if version != "003" {
+    if version != "002" && version != "003" {
         return Err(CryptoError::UnsupportedScheme(version.to_string()));
     }
 
@@ -40,8 +120,8 @@ fn decrypt(s: &str, ek: &Key, ak: &Key, check_uuid: &Uuid) -> Result<String, Cry
         return Err(CryptoError::UuidMismatch);
     }
 
-    let to_auth = std::format!("003:{}:{}:{}", uuid, iv, ciphertext);
-    let auth_hash_bytes = HEXLOWER.decode(&auth_hash.as_bytes())?;
+    let to_auth = std::format!("{}:{}:{}:{}", version, s[2], iv, ciphertext);
+    let auth_hash_bytes = HEXLOWER.decode(auth_hash.as_bytes())?;
     let key = hmac::Key::new(hmac::HMAC_SHA256, ak);
 
     if let Err(error::Unspecified) = hmac::verify(&key, to_auth.as_bytes(), &auth_hash_bytes) {
@@ -55,7 +135,7 @@ fn decrypt(s: &str, ek: &Key, ak: &Key, check_uuid: &Uuid) -> Result<String, Cry
     Ok(str::from_utf8(decrypted.as_ref())?.to_string())
 }
 
-fn encrypt(s: &str, ek: &Key, ak: &Key, uuid: &Uuid) -> Result<String, CryptoError> {
+fn encrypt_003(s: &str, ek: &Key, ak: &Key, uuid: &Uuid) -> Result<String, CryptoError> {
     let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
     let mut iv_bytes = [0u8; 16];
     rng.fill_bytes(&mut iv_bytes);
@@ -80,6 +160,79 @@ fn encrypt(s: &str, ek: &Key, ak: &Key, uuid: &Uuid) -> Result<String, CryptoErr
     ))
 }
 
+fn decrypt_004(s: &str, key: &Key, check_uuid: &Uuid) -> Result<String, CryptoError> {
+    let s: Vec<&str> = s.split(':').collect();
+
+    if s.len() != 4 {
+        return Err(CryptoError::Malformed);
+    }
+
+    let version = s[0];
+    let nonce = s[1];
+    let ciphertext = s[2];
+    let authenticated_data = s[3];
+
+    if version != "004" {
+        return Err(CryptoError::UnsupportedScheme(version.to_string()));
+    }
+
+    let decoded_data = BASE64.decode(authenticated_data.as_bytes())?;
+    let decoded_data: AuthenticatedData = serde_json::from_slice(&decoded_data)
+        .map_err(|_| CryptoError::Malformed)?;
+
+    if &decoded_data.u != check_uuid {
+        return Err(CryptoError::UuidMismatch);
+    }
+
+    if decoded_data.v != "004" {
+        return Err(CryptoError::UnsupportedScheme(decoded_data.v));
+    }
+
+    let nonce_bytes: [u8; 24] = HEXLOWER
+        .decode(nonce.as_bytes())?
+        .as_slice()
+        .try_into()
+        .map_err(|_| CryptoError::Malformed)?;
+
+    let ciphertext_bytes = BASE64.decode(ciphertext.as_bytes())?;
+    let cipher = XChaCha20Poly1305::new(&(*key).into());
+    let payload = Payload {
+        msg: ciphertext_bytes.as_ref(),
+        aad: authenticated_data.as_bytes(),
+    };
+
+    let decrypted = cipher
+        .decrypt(&nonce_bytes.into(), payload)
+        .map_err(|_| CryptoError::Verification)?;
+
+    Ok(str::from_utf8(decrypted.as_ref())?.to_string())
+}
+
+fn encrypt_004(s: &str, key: &Key, authenticated_data: &AuthenticatedData) -> Result<String, CryptoError> {
+    let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
+    let mut nonce_bytes = [0u8; 24];
+    rng.fill_bytes(&mut nonce_bytes);
+
+    let authenticated_data = serde_json::to_string(authenticated_data).map_err(anyhow::Error::from)?;
+    let authenticated_data = BASE64.encode(authenticated_data.as_bytes());
+    let cipher = XChaCha20Poly1305::new(&(*key).into());
+    let payload = Payload {
+        msg: s.as_bytes(),
+        aad: authenticated_data.as_bytes(),
+    };
+
+    let encrypted = cipher
+        .encrypt(&nonce_bytes.into(), payload)
+        .map_err(|_| CryptoError::Verification)?;
+
+    Ok(std::format!(
+        "004:{}:{}:{}",
+        HEXLOWER.encode(nonce_bytes.as_ref()),
+        BASE64.encode(encrypted.as_slice()),
+        authenticated_data
+    ))
+}
+
 /// Create random nonce.
 pub fn make_nonce() -> String {
     let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
@@ -88,59 +241,250 @@ pub fn make_nonce() -> String {
     HEXLOWER.encode(nonce.as_ref())
 }
 
+/// Create random hex-encoded 256 bit key.
+pub fn make_key() -> String {
+    make_nonce()
+}
+
+/// Derive password, master and authentication key according to the 003 scheme.
+fn derive_003(credentials: &Credentials) -> Result<(Key, Key, Key), CryptoError> {
+    let cost = NonZeroU32::new(credentials.cost).ok_or(CryptoError::InvalidCost)?;
+    let salt_input = std::format!("{}:SF:003:{}:{}", credentials.identifier, credentials.cost, credentials.nonce);
+    let salt = digest::digest(&digest::SHA256, salt_input.as_bytes());
+    let hex_salt = HEXLOWER.encode(salt.as_ref());
+    let mut hashed = [0u8; 768 / 8];
+
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA512,
+        cost,
+        hex_salt.as_bytes(),
+        credentials.password.as_bytes(),
+        &mut hashed,
+    );
+
+    let mut pw: Key = [0u8; 32];
+    let mut mk: Key = [0u8; 32];
+    let mut ak: Key = [0u8; 32];
+
+    pw.clone_from_slice(&hashed[0..32]);
+    mk.clone_from_slice(&hashed[32..64]);
+    ak.clone_from_slice(&hashed[64..]);
+
+    Ok((pw, mk, ak))
+}
+
+/// Derive keys according to the 001 and 002 schemes from the salt stored as nonce. 001 has no
+/// authentication key, so its derived key is only 512 bits long.
+fn derive_legacy(credentials: &Credentials) -> Result<(Key, Key, Key), CryptoError> {
+    let cost = NonZeroU32::new(credentials.cost).ok_or(CryptoError::InvalidCost)?;
+    let length = if credentials.version == "001" { 512 / 8 } else { 768 / 8 };
+    let mut hashed = vec![0u8; length];
+
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA512,
+        cost,
+        credentials.nonce.as_bytes(),
+        credentials.password.as_bytes(),
+        &mut hashed,
+    );
+
+    let mut pw: Key = [0u8; 32];
+    let mut mk: Key = [0u8; 32];
+    let mut ak: Key = [0u8; 32];
+
+    pw.clone_from_slice(&hashed[0..32]);
+    mk.clone_from_slice(&hashed[32..64]);
+
+    if length > 64 {
+        ak.clone_from_slice(&hashed[64..]);
+    }
+
+    Ok((pw, mk, ak))
+}
+
+/// Derive server password and master key according to the 004 scheme. There is no separate
+/// authentication key because XChaCha20-Poly1305 authenticates by itself.
+fn derive_004(credentials: &Credentials) -> Result<(Key, Key), CryptoError> {
+    let salt_input = std::format!("{}:{}", credentials.identifier, credentials.nonce);
+    let digest = digest::digest(&digest::SHA256, salt_input.as_bytes());
+
+    // The salt is the first half of the hex-encoded digest, i.e. its first 16 bytes.
+    let salt = &digest.as_ref()[..16];
+
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: 65536,
+        time_cost: 5,
+        lanes: 1,
+        thread_mode: argon2::ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: 64,
+    };
+
+    let hashed = argon2::hash_raw(credentials.password.as_bytes(), salt, &config)?;
+
+    let mut mk: Key = [0u8; 32];
+    let mut pw: Key = [0u8; 32];
+
+    mk.clone_from_slice(&hashed[0..32]);
+    pw.clone_from_slice(&hashed[32..]);
+
+    Ok((pw, mk))
+}
+
 impl Crypto {
     pub fn new(credentials: &Credentials) -> Result<Self, CryptoError> {
-        let cost = NonZeroU32::new(credentials.cost).ok_or(CryptoError::InvalidCost)?;
-        let salt_input = std::format!("{}:SF:003:{}:{}", credentials.identifier, credentials.cost, credentials.nonce);
-        let salt = digest::digest(&digest::SHA256, salt_input.as_bytes());
-        let hex_salt = HEXLOWER.encode(salt.as_ref());
-        let mut hashed = [0u8; 768 / 8];
-
-        ring::pbkdf2::derive(
-            ring::pbkdf2::PBKDF2_HMAC_SHA512,
-            cost,
-            &hex_salt.as_bytes(),
-            credentials.password.as_bytes(),
-            &mut hashed,
-        );
-
-        let mut pw: Key = [0u8; 32];
-        let mut mk: Key = [0u8; 32];
-        let mut ak: Key = [0u8; 32];
-
-        pw.clone_from_slice(&hashed[0..32]);
-        mk.clone_from_slice(&hashed[32..64]);
-        ak.clone_from_slice(&hashed[64..]);
+        let (pw, mk, ak) = match credentials.version.as_str() {
+            "001" | "002" => derive_legacy(credentials)?,
+            "003" => derive_003(credentials)?,
+            "004" => {
+                let (pw, mk) = derive_004(credentials)?;
+                (pw, mk, [0u8; 32])
+            }
+            version => return Err(CryptoError::UnsupportedScheme(version.to_string())),
+        };
 
-        Ok(Crypto { pw: pw, mk: mk, ak: ak })
+        Ok(Crypto {
+            version: credentials.version.clone(),
+            identifier: credentials.identifier.clone(),
+            nonce: credentials.nonce.clone(),
+            pw,
+            mk,
+            ak,
+            items_keys: HashMap::new(),
+            default_items_key: None,
+        })
+    }
+
+    /// Register a decrypted items key so items referring to it can be decrypted. If it is marked
+    /// as default, it is used to encrypt items from now on.
+    pub fn add_items_key(&mut self, items_key: &ItemsKey) -> Result<(), CryptoError> {
+        let mut key: Key = [0; 32];
+
+        HEXLOWER
+            .decode_mut(items_key.key.as_bytes(), &mut key)
+            .map_err(|partial| partial.error)?;
+
+        self.items_keys.insert(items_key.uuid, key);
+
+        if items_key.is_default || self.default_items_key.is_none() {
+            self.default_items_key = Some(items_key.uuid);
+        }
+
+        Ok(())
+    }
+
+    /// Return true if the items key is known.
+    pub fn has_items_key(&self, uuid: &Uuid) -> bool {
+        self.items_keys.contains_key(uuid)
+    }
+
+    /// Return uuid of the items key used for encryption.
+    pub fn default_items_key(&self) -> Option<Uuid> {
+        self.default_items_key
+    }
+
+    /// Return true if items are encrypted with items keys rather than the root key.
+    pub fn uses_items_keys(&self) -> bool {
+        self.version == "004"
     }
 
     pub fn password(&self) -> String {
         HEXLOWER.encode(&self.pw)
     }
 
-    pub fn decrypt(&self, item: &Envelope) -> Result<String, CryptoError> {
-        if item.enc_item_key.is_none() || item.content.is_none() {
-            return Err(CryptoError::NoKey);
-        }
+    /// Key parameters authenticated with items encrypted by the root key.
+    fn key_params(&self) -> serde_json::Value {
+        serde_json::json!({
+            "identifier": self.identifier,
+            "pw_nonce": self.nonce,
+            "version": self.version,
+        })
+    }
 
+    pub fn decrypt(&self, item: &Envelope) -> Result<String, CryptoError> {
         let enc_item_key = item.enc_item_key.as_ref().ok_or(CryptoError::NoKey)?;
         let content = item.content.as_ref().ok_or(CryptoError::NoContent)?;
-        let item_key = decrypt(&enc_item_key, &self.mk, &self.ak, &item.uuid)?;
-        let mut item_ek: Key = [0; 32];
-        let mut item_ak: Key = [0; 32];
+        let version = version_of(content);
 
-        HEXLOWER
-            .decode_mut(item_key[..64].as_bytes(), &mut item_ek)
-            .expect("foo");
-        HEXLOWER
-            .decode_mut(item_key[64..].as_bytes(), &mut item_ak)
-            .expect("foo");
+        // 002 accounts may still contain items encrypted with 001 which uses the same master key.
+        let compatible = |version: &str| version == self.version || (self.version == "002" && version == "001");
 
-        Ok(decrypt(&content, &item_ek, &item_ak, &item.uuid)?)
+        if !compatible(version) || !compatible(version_of(enc_item_key)) {
+            return Err(CryptoError::UnsupportedScheme(version.to_string()));
+        }
+
+        match version {
+            "001" => {
+                let (item_ek, item_ak) = split_item_key(&decrypt_001(enc_item_key, &self.mk)?)?;
+                let auth_hash = item.auth_hash.as_ref().ok_or(CryptoError::Verification)?;
+                let auth_hash_bytes = HEXLOWER.decode(auth_hash.as_bytes())?;
+                let key = hmac::Key::new(hmac::HMAC_SHA256, &item_ak);
+
+                if let Err(error::Unspecified) = hmac::verify(&key, content.as_bytes(), &auth_hash_bytes) {
+                    return Err(CryptoError::Verification);
+                };
+
+                decrypt_001(content, &item_ek)
+            }
+            "002" | "003" => {
+                let item_key = decrypt_003(enc_item_key, &self.mk, &self.ak, &item.uuid)?;
+                let (item_ek, item_ak) = split_item_key(&item_key)?;
+                decrypt_003(content, &item_ek, &item_ak, &item.uuid)
+            }
+            "004" => {
+                let key = match item.items_key_id {
+                    Some(uuid) => self.items_keys.get(&uuid).ok_or(CryptoError::UnknownItemsKey(uuid))?,
+                    None => &self.mk,
+                };
+
+                let item_key = decrypt_004(enc_item_key, key, &item.uuid)?;
+
+                if item_key.len() != 64 {
+                    return Err(CryptoError::Malformed);
+                }
+
+                let mut item_k: Key = [0; 32];
+
+                HEXLOWER
+                    .decode_mut(item_key.as_bytes(), &mut item_k)
+                    .map_err(|partial| partial.error)?;
+
+                decrypt_004(content, &item_k, &item.uuid)
+            }
+            version => Err(CryptoError::UnsupportedScheme(version.to_string())),
+        }
     }
 
+    /// Encrypt content with a fresh item key, which in turn is encrypted with the default items
+    /// key or, for schemes without items keys, the root key.
     pub fn encrypt(&self, content: &str, uuid: &Uuid) -> Result<Encrypted, CryptoError> {
+        match self.version.as_str() {
+            "003" => self.encrypt_003(content, uuid),
+            "004" => {
+                let items_key_id = self.default_items_key.ok_or(CryptoError::NoItemsKey)?;
+                let items_key = self.items_keys.get(&items_key_id).ok_or(CryptoError::NoItemsKey)?;
+                let mut encrypted = self.encrypt_004(content, uuid, items_key, None)?;
+                encrypted.items_key_id = Some(items_key_id);
+                Ok(encrypted)
+            }
+            version => Err(CryptoError::UnsupportedScheme(version.to_string())),
+        }
+    }
+
+    /// Encrypt content with a fresh item key, which in turn is encrypted with the root key. This
+    /// is only meant for items keys themselves.
+    pub fn encrypt_with_root_key(&self, content: &str, uuid: &Uuid) -> Result<Encrypted, CryptoError> {
+        match self.version.as_str() {
+            "003" => self.encrypt_003(content, uuid),
+            "004" => self.encrypt_004(content, uuid, &self.mk, Some(self.key_params())),
+            version => Err(CryptoError::UnsupportedScheme(version.to_string())),
+        }
+    }
+
+    fn encrypt_003(&self, content: &str, uuid: &Uuid) -> Result<Encrypted, CryptoError> {
         let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
         let mut item_key = [0u8; 64];
         rng.fill_bytes(&mut item_key);
@@ -151,14 +495,38 @@ impl Crypto {
         item_ek.clone_from_slice(&item_key[..32]);
         item_ak.clone_from_slice(&item_key[32..]);
 
-        let mut iv_bytes = [0u8; 16];
-        rng.fill_bytes(&mut iv_bytes);
+        let item_key_encoded = HEXLOWER.encode(item_key.as_ref());
+
+        Ok(Encrypted {
+            content: encrypt_003(content, &item_ek, &item_ak, uuid)?,
+            enc_item_key: encrypt_003(item_key_encoded.as_ref(), &self.mk, &self.ak, uuid)?,
+            items_key_id: None,
+        })
+    }
+
+    fn encrypt_004(&self, content: &str, uuid: &Uuid, key: &Key, kp: Option<serde_json::Value>) -> Result<Encrypted, CryptoError> {
+        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
+        let mut item_key: Key = [0; 32];
+        rng.fill_bytes(&mut item_key);
 
         let item_key_encoded = HEXLOWER.encode(item_key.as_ref());
 
+        let content_data = AuthenticatedData {
+            kp: None,
+            u: *uuid,
+            v: "004".to_string(),
+        };
+
+        let key_data = AuthenticatedData {
+            kp,
+            u: *uuid,
+            v: "004".to_string(),
+        };
+
         Ok(Encrypted {
-            content: encrypt(content, &item_ek, &item_ak, &uuid)?,
-            enc_item_key: encrypt(item_key_encoded.as_ref(), &self.mk, &self.ak, &uuid)?,
+            content: encrypt_004(content, &item_key, &content_data)?,
+            enc_item_key: encrypt_004(&item_key_encoded, key, &key_data)?,
+            items_key_id: None,
         })
     }
 }
@@ -166,11 +534,36 @@ impl Crypto {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Note, Item};
+    use crate::{Exported, Note, NoteFlags, Item, ItemsKey, Other, Tag};
     use chrono::Utc;
 
-    #[test]
-    fn test_encrypt_decrypt() {
+    fn make_credentials(version: &str) -> Credentials {
+        let nonce = "3f8ea1ffd8067c1550ca3ad78de71c9b6e68b5cb540e370c12065eca15d9a049";
+
+        Credentials {
+            identifier: "foo@bar.com".to_string(),
+            cost: 110000,
+            nonce: nonce.to_string(),
+            password: "secret".to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    fn make_envelope(uuid: Uuid, encrypted: Encrypted) -> Envelope {
+        Envelope {
+            uuid,
+            content: Some(encrypted.content),
+            content_type: "Note".to_string(),
+            enc_item_key: Some(encrypted.enc_item_key),
+            items_key_id: encrypted.items_key_id,
+            auth_hash: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            deleted: Some(false),
+        }
+    }
+
+    fn encrypt_decrypt(version: &str) {
         let now = Utc::now();
         let uuid = Uuid::new_v4();
 
@@ -179,30 +572,306 @@ mod tests {
             text: "Text".to_owned(),
             created_at: now,
             updated_at: now,
-            uuid: uuid,
+            uuid,
+            flags: Default::default(),
+            extra: Default::default(),
         };
 
-        let nonce = "3f8ea1ffd8067c1550ca3ad78de71c9b6e68b5cb540e370c12065eca15d9a049";
-        let credentials = Credentials {
-            identifier: "foo@bar.com".to_string(),
-            cost: 110000,
-            nonce: nonce.to_string(),
-            password: "secret".to_string(),
-        };
-        let crypto = Crypto::new(&credentials).unwrap();
+        let mut crypto = Crypto::new(&make_credentials(version)).unwrap();
+        crypto.add_items_key(&ItemsKey::new(version, true)).unwrap();
 
         let item = Item::Note(note);
         let encrypted = item.encrypt(&crypto).unwrap();
+
+        assert!(encrypted.content.as_ref().unwrap().starts_with(version));
+        assert!(encrypted.enc_item_key.as_ref().unwrap().starts_with(version));
+
         let decrypted = encrypted.decrypt(&crypto).unwrap();
 
         assert!(matches!(decrypted, Item::Note { .. }));
 
-        match decrypted {
-            Item::Note(decrypted) => {
-                assert_eq!(decrypted.title, "Title");
-                assert_eq!(decrypted.text, "Text");
-            },
-            _ => {}
+        if let Item::Note(decrypted) = decrypted {
+            assert_eq!(decrypted.title, "Title");
+            assert_eq!(decrypted.text, "Text");
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        encrypt_decrypt("003");
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_004() {
+        encrypt_decrypt("004");
+    }
+
+    #[test]
+    fn test_004_tampered_authenticated_data() {
+        let crypto = Crypto::new(&make_credentials("004")).unwrap();
+        let uuid = Uuid::new_v4();
+        let mut encrypted = crypto.encrypt_with_root_key("secret content", &uuid).unwrap();
+
+        // Swap the authenticated data with one claiming a different uuid.
+        let other = crypto.encrypt_with_root_key("secret content", &Uuid::new_v4()).unwrap();
+        let mut parts: Vec<&str> = encrypted.content.split(':').collect();
+        parts[3] = other.content.split(':').nth(3).unwrap();
+        encrypted.content = parts.join(":");
+
+        let envelope = make_envelope(uuid, encrypted);
+
+        assert!(matches!(crypto.decrypt(&envelope), Err(CryptoError::UuidMismatch)));
+    }
+
+    #[test]
+    fn test_scheme_mismatch() {
+        let crypto_003 = Crypto::new(&make_credentials("003")).unwrap();
+        let crypto_004 = Crypto::new(&make_credentials("004")).unwrap();
+        let uuid = Uuid::new_v4();
+        let encrypted = crypto_003.encrypt("content", &uuid).unwrap();
+
+        let envelope = make_envelope(uuid, encrypted);
+
+        assert!(matches!(crypto_004.decrypt(&envelope), Err(CryptoError::UnsupportedScheme(_))));
+    }
+
+    #[test]
+    fn test_items_key_hierarchy() {
+        let credentials = make_credentials("004");
+        let mut crypto = Crypto::new(&credentials).unwrap();
+        let items_key = ItemsKey::new("004", true);
+        crypto.add_items_key(&items_key).unwrap();
+
+        let now = Utc::now();
+        let note = Item::Note(Note {
+            title: "Title".to_owned(),
+            text: "Text".to_owned(),
+            created_at: now,
+            updated_at: now,
+            uuid: Uuid::new_v4(),
+            flags: Default::default(),
+            extra: Default::default(),
+        });
+
+        let encrypted_key = Item::ItemsKey(items_key).encrypt(&crypto).unwrap();
+        let encrypted_note = note.encrypt(&crypto).unwrap();
+
+        assert_eq!(encrypted_key.items_key_id, None);
+        assert_eq!(encrypted_note.items_key_id, crypto.default_items_key());
+
+        // A fresh crypto only knows the root key and must learn the items key first.
+        let mut other = Crypto::new(&credentials).unwrap();
+
+        assert!(matches!(encrypted_note.decrypt(&other), Err(CryptoError::UnknownItemsKey(_))));
+
+        match encrypted_key.decrypt(&other).unwrap() {
+            Item::ItemsKey(items_key) => other.add_items_key(&items_key).unwrap(),
+            _ => panic!("expected items key"),
+        }
+
+        assert!(matches!(encrypted_note.decrypt(&other).unwrap(), Item::Note { .. }));
+    }
+
+    #[test]
+    fn test_other_content_type() {
+        let mut crypto = Crypto::new(&make_credentials("004")).unwrap();
+        crypto.add_items_key(&ItemsKey::new("004", true)).unwrap();
+
+        let now = Utc::now();
+        let content = r#"{"name":"Dark","package_info":{"version":"1.0"},"references":[]}"#;
+
+        let theme = Item::Other(Other {
+            content_type: "SN|Theme".to_owned(),
+            content: content.to_owned(),
+            created_at: now,
+            updated_at: now,
+            uuid: Uuid::new_v4(),
+        });
+
+        let encrypted = theme.encrypt(&crypto).unwrap();
+        assert_eq!(encrypted.content_type, "SN|Theme");
+
+        match encrypted.decrypt(&crypto).unwrap() {
+            Item::Other(other) => {
+                assert_eq!(other.content_type, "SN|Theme");
+                assert_eq!(other.content, content);
+            }
+            _ => panic!("expected other item"),
+        }
+    }
+
+    #[test]
+    fn test_note_extra_fields() {
+        let mut crypto = Crypto::new(&make_credentials("004")).unwrap();
+        crypto.add_items_key(&ItemsKey::new("004", true)).unwrap();
+
+        let uuid = Uuid::new_v4();
+        let content = r#"{"title":"Title","text":"Text","appData":{"org.standardnotes.sn":{"pinned":true}},"preview_plain":"Text"}"#;
+        let encrypted = crypto.encrypt(content, &uuid).unwrap();
+
+        let envelope = make_envelope(uuid, encrypted);
+
+        let mut note = match envelope.decrypt(&crypto).unwrap() {
+            Item::Note(note) => note,
+            _ => panic!("expected note"),
+        };
+
+        note.text = "Edited".to_owned();
+
+        let decrypted = crypto.decrypt(&Item::Note(note).encrypt(&crypto).unwrap()).unwrap();
+        let value = serde_json::from_str::<serde_json::Value>(&decrypted).unwrap();
+
+        assert_eq!(value["text"], "Edited");
+        assert_eq!(value["appData"]["org.standardnotes.sn"]["pinned"], true);
+        assert_eq!(value["preview_plain"], "Text");
+    }
+
+    #[test]
+    fn test_note_flags() {
+        let mut crypto = Crypto::new(&make_credentials("004")).unwrap();
+        crypto.add_items_key(&ItemsKey::new("004", true)).unwrap();
+
+        let uuid = Uuid::new_v4();
+        let content = r#"{"title":"Title","text":"Text","trashed":true,"appData":{"org.standardnotes.sn":{"pinned":true,"archived":false,"client_updated_at":"2020-01-01"}}}"#;
+        let encrypted = crypto.encrypt(content, &uuid).unwrap();
+
+        let envelope = make_envelope(uuid, encrypted);
+
+        let mut note = match envelope.decrypt(&crypto).unwrap() {
+            Item::Note(note) => note,
+            _ => panic!("expected note"),
+        };
+
+        assert_eq!(note.flags, NoteFlags { pinned: true, trashed: true, ..Default::default() });
+
+        note.flags.trashed = false;
+        note.flags.locked = true;
+
+        let decrypted = crypto.decrypt(&Item::Note(note).encrypt(&crypto).unwrap()).unwrap();
+        let value = serde_json::from_str::<serde_json::Value>(&decrypted).unwrap();
+        let app_data = &value["appData"]["org.standardnotes.sn"];
+
+        assert!(value.get("trashed").is_none());
+        assert_eq!(app_data["pinned"], true);
+        assert_eq!(app_data["locked"], true);
+        assert!(app_data.get("archived").is_none());
+        assert_eq!(app_data["client_updated_at"], "2020-01-01");
+    }
+
+    #[test]
+    fn test_tag_round_trip() {
+        let mut crypto = Crypto::new(&make_credentials("004")).unwrap();
+        crypto.add_items_key(&ItemsKey::new("004", true)).unwrap();
+
+        let parent = Tag::new("Work");
+        let note = Uuid::new_v4();
+        let mut tag = Tag::new("Meetings");
+        assert!(tag.add_note(note));
+        assert!(!tag.add_note(note));
+        tag.set_parent(Some(parent.uuid));
+
+        let envelope = Item::Tag(tag).encrypt(&crypto).unwrap();
+        assert_eq!(envelope.content_type, "Tag");
+
+        let value = serde_json::from_str::<serde_json::Value>(&crypto.decrypt(&envelope).unwrap()).unwrap();
+        assert_eq!(value["references"][0]["content_type"], "Note");
+        assert!(value["references"][0].get("reference_type").is_none());
+        assert_eq!(value["references"][1]["content_type"], "Tag");
+        assert_eq!(value["references"][1]["reference_type"], "TagToParentTag");
+
+        let mut tag = match envelope.decrypt(&crypto).unwrap() {
+            Item::Tag(tag) => tag,
+            _ => panic!("expected tag"),
+        };
+
+        assert_eq!(tag.title, "Meetings");
+        assert_eq!(tag.parent(), Some(parent.uuid));
+        assert_eq!(tag.notes().collect::<Vec<_>>(), vec![note]);
+
+        assert!(tag.remove_note(&note));
+        assert!(!tag.has_note(&note));
+        tag.set_parent(None);
+        assert!(tag.references.is_empty());
+    }
+
+    /// Nonce of the key derivation test vectors of the Standard Notes reference client.
+    const REFERENCE_NONCE: &str = "baaec0131d677cf993381367eb082fe377cefe70118c1699cb9b38f0bc850e7b";
+
+    #[test]
+    fn test_derive_known_answers() {
+        let mut credentials = Credentials {
+            identifier: "foo@bar.com".to_string(),
+            cost: 110000,
+            nonce: REFERENCE_NONCE.to_string(),
+            password: "very_secure".to_string(),
+            version: "004".to_string(),
         };
+
+        let crypto = Crypto::new(&credentials).unwrap();
+        assert_eq!(crypto.password(), "83707dfc837b3fe52b317be367d3ed8e14e903b2902760884fd0246a77c2299d");
+        assert_eq!(HEXLOWER.encode(&crypto.mk), "5d68e78b56d454e32e1f5dbf4c4e7cf25d74dc1efc942e7c9dfce572c1f3b943");
+
+        credentials.version = "003".to_string();
+        let crypto = Crypto::new(&credentials).unwrap();
+        assert_eq!(crypto.password(), "60fdae231049d81974c562e943ad472f0143daa87f43048d2ede2d199ea7be25");
+    }
+
+    /// Decrypt an export with fixed keys, nonces and IVs and return its only note. The fixtures
+    /// were produced independently of this crate.
+    fn decrypt_export(export: &str) -> (Crypto, Envelope, Note) {
+        let exported = Exported::from_str(export).unwrap();
+        let mut crypto = Crypto::new(&Credentials::from_exported(&exported, "very_secure")).unwrap();
+        let (keys, notes): (Vec<_>, Vec<_>) = exported.items.into_iter().partition(|item| item.content_type == "SN|ItemsKey");
+
+        for key in &keys {
+            match key.decrypt(&crypto).unwrap() {
+                Item::ItemsKey(items_key) => crypto.add_items_key(&items_key).unwrap(),
+                _ => panic!("expected items key"),
+            }
+        }
+
+        let envelope = notes.into_iter().next().unwrap();
+
+        match envelope.decrypt(&crypto).unwrap() {
+            Item::Note(note) => (crypto, envelope, note),
+            _ => panic!("expected note"),
+        }
     }
+
+    #[test]
+    fn test_decrypt_export_004() {
+        let (crypto, _, note) = decrypt_export(include_str!("../tests/fixtures/export-004.json"));
+
+        assert_eq!(crypto.default_items_key(), Uuid::parse_str("7f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0").ok());
+        assert_eq!(note.title, "Known answer");
+        assert_eq!(note.text, "Decrypted with fixed inputs.");
+    }
+
+    #[test]
+    fn test_decrypt_export_003() {
+        let (_, _, note) = decrypt_export(include_str!("../tests/fixtures/export-003.json"));
+
+        assert_eq!(note.title, "Known answer");
+        assert_eq!(note.text, "Decrypted with fixed inputs.");
+    }
+
+    #[test]
+    fn test_decrypt_legacy_exports() {
+        for export in &[include_str!("../tests/fixtures/export-001.json"), include_str!("../tests/fixtures/export-002.json")] {
+            let (crypto, mut envelope, note) = decrypt_export(export);
+
+            assert_eq!(note.title, "Known answer");
+            assert_eq!(note.text, "Decrypted with fixed inputs.");
+
+            // Legacy schemes are read-only.
+            assert!(matches!(crypto.encrypt("content", &note.uuid), Err(CryptoError::UnsupportedScheme(_))));
+
+            // 001 authenticates the ciphertext separately.
+            if envelope.auth_hash.is_some() {
+                envelope.auth_hash = Some(HEXLOWER.encode(&[0u8; 32]));
+                assert!(matches!(crypto.decrypt(&envelope), Err(CryptoError::Verification)));
+            }
+        }
+    }
+
 }
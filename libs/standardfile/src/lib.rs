@@ -6,18 +6,23 @@ use chrono::{DateTime, Utc};
 use data_encoding::DecodeError;
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
 use thiserror::Error;
 use std::str::Utf8Error;
 
 pub mod crypto;
 pub mod remote;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Envelope {
     pub uuid: Uuid,
     pub content: Option<String>,
     pub content_type: String,
     pub enc_item_key: Option<String>,
+    #[serde(default)]
+    pub items_key_id: Option<Uuid>,
+    #[serde(default)]
+    pub auth_hash: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub deleted: Option<bool>,
@@ -25,9 +30,15 @@ pub struct Envelope {
 
 #[derive(Serialize, Deserialize)]
 pub struct AuthParams {
+    #[serde(alias = "email")]
     pub identifier: String,
+    #[serde(default)]
     pub pw_cost: u32,
+    #[serde(default)]
     pub pw_nonce: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pw_salt: Option<String>,
+    #[serde(default = "legacy_version")]
     pub version: String,
 }
 
@@ -42,18 +53,53 @@ pub struct Exported {
 struct NoteContent {
     pub title: Option<String>,
     pub text: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ItemsKeyContent {
+    pub items_key: String,
+    pub version: String,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+/// Reference type of a tag pointing to its parent tag.
+pub static TAG_TO_PARENT_TAG: &str = "TagToParentTag";
+
+/// Reference from one item to another.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Reference {
     pub uuid: Uuid,
+    /// Content type of the referenced item.
     pub content_type: String,
+    /// Kind of reference for references that do not simply mean "contains".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_type: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct TagContent {
     pub title: String,
     pub references: Vec<Reference>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// App data domain Standard Notes clients keep note flags in.
+static APP_DATA_DOMAIN: &str = "org.standardnotes.sn";
+
+/// Flags Standard Notes clients set on notes. `trashed` is a content field of its own, the others
+/// are kept in the app data of the `org.standardnotes.sn` domain.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NoteFlags {
+    pub pinned: bool,
+    pub archived: bool,
+    pub trashed: bool,
+    /// Locked notes must not be edited.
+    pub locked: bool,
 }
 
 pub struct Note {
@@ -62,11 +108,39 @@ pub struct Note {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub uuid: Uuid,
+    pub flags: NoteFlags,
+    /// Content fields we do not use ourselves but other clients do, such as `appData`. They are
+    /// written back unchanged on encryption.
+    pub extra: Map<String, Value>,
 }
 
 pub struct Tag {
     pub title: String,
-    pub references: Vec<Uuid>,
+    /// Tagged notes and, for nested tags, the parent tag.
+    pub references: Vec<Reference>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub uuid: Uuid,
+    /// Content fields we do not use ourselves, written back unchanged on encryption.
+    pub extra: Map<String, Value>,
+}
+
+/// Key used to encrypt the item keys of 004 items. It is itself encrypted with the root key.
+pub struct ItemsKey {
+    pub key: String,
+    pub version: String,
+    pub is_default: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub uuid: Uuid,
+}
+
+/// Item of a content type we do not handle ourselves, e.g. components, preferences, smart tags or
+/// themes. The decrypted content is kept as is, so these survive export, import and sync.
+pub struct Other {
+    pub content_type: String,
+    /// Decrypted JSON content.
+    pub content: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub uuid: Uuid,
@@ -75,6 +149,8 @@ pub struct Tag {
 pub enum Item {
     Note(Note),
     Tag(Tag),
+    ItemsKey(ItemsKey),
+    Other(Other),
 }
 
 #[derive(Error, Debug)]
@@ -85,16 +161,22 @@ pub enum CryptoError {
     NoKey,
     #[error("no encrypted content given")]
     NoContent,
-    #[error("unknown item content type `{0}'")]
-    UnknownContentType(String),
     #[error("unsupported encryption scheme {0}")]
     UnsupportedScheme(String),
     #[error("uuid mismatch")]
     UuidMismatch,
+    #[error("malformed encrypted string")]
+    Malformed,
+    #[error("no default items key")]
+    NoItemsKey,
+    #[error("unknown items key {0}")]
+    UnknownItemsKey(Uuid),
     #[error("uuid decode error")]
     UuidDecode(#[from] uuid::Error),
     #[error("verification issue")]
     Verification,
+    #[error("key derivation error")]
+    KeyDerivation(#[from] argon2::Error),
     #[error("block mode error")]
     BlockMode(#[from] BlockModeError),
     #[error("iv length error")]
@@ -107,14 +189,20 @@ pub enum CryptoError {
     Utf8Decode(#[from] Utf8Error),
 }
 
+/// Very old exports do not record the version at all.
+fn legacy_version() -> String {
+    "001".to_string()
+}
+
 /// Authentication parameters constructed locally, from a remote server or an imported file and
 /// passed to construct the crypto used in the storage.
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Credentials {
     pub identifier: String,
     pub cost: u32,
     pub nonce: String,
     pub password: String,
+    pub version: String,
 }
 
 impl AuthParams {
@@ -123,13 +211,15 @@ impl AuthParams {
             identifier: credentials.identifier.clone(),
             pw_cost: credentials.cost,
             pw_nonce: credentials.nonce.clone(),
-            version: "003".to_string(),
+            pw_salt: None,
+            version: credentials.version.clone(),
         }
     }
 }
 
 impl Envelope {
     /// Deserialize Envelope from JSON string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Self> {
         Ok(serde_json::from_str(s)?)
     }
@@ -141,14 +231,23 @@ impl Envelope {
 
     /// Decrypt Envelope to an Item.
     pub fn decrypt(&self, crypto: &crypto::Crypto) -> Result<Item, CryptoError> {
+        if let Some(items_key_id) = self.items_key_id {
+            if !crypto.has_items_key(&items_key_id) {
+                return Err(CryptoError::UnknownItemsKey(items_key_id));
+            }
+        }
+
         if self.content_type == "Note" {
-            Ok(Note::decrypt(crypto, &self)?)
+            Ok(Note::decrypt(crypto, self)?)
         }
         else if self.content_type == "Tag" {
-            Ok(Tag::decrypt(crypto, &self)?)
+            Ok(Tag::decrypt(crypto, self)?)
+        }
+        else if self.content_type == "SN|ItemsKey" {
+            Ok(ItemsKey::decrypt(crypto, self)?)
         }
         else {
-            Err(CryptoError::UnknownContentType(self.content_type.clone()))
+            Ok(Other::decrypt(crypto, self)?)
         }
     }
 }
@@ -159,6 +258,8 @@ impl Item {
         match self {
             Item::Note(note) => note.encrypt(crypto),
             Item::Tag(tag) => tag.encrypt(crypto),
+            Item::ItemsKey(items_key) => items_key.encrypt(crypto),
+            Item::Other(other) => other.encrypt(crypto),
         }
     }
 
@@ -167,12 +268,27 @@ impl Item {
         match self {
             Item::Note(note) => note.uuid,
             Item::Tag(tag) => tag.uuid,
+            Item::ItemsKey(items_key) => items_key.uuid,
+            Item::Other(other) => other.uuid,
+        }
+    }
+
+    /// Move item to a different uuid.
+    pub fn with_uuid(mut self, uuid: Uuid) -> Self {
+        match &mut self {
+            Item::Note(note) => note.uuid = uuid,
+            Item::Tag(tag) => tag.uuid = uuid,
+            Item::ItemsKey(items_key) => items_key.uuid = uuid,
+            Item::Other(other) => other.uuid = uuid,
         }
+
+        self
     }
 }
 
 impl Exported {
     /// Deserialize Exported from JSON string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Self> {
         Ok(serde_json::from_str(s)?)
     }
@@ -184,29 +300,94 @@ impl Exported {
 
 impl Credentials {
     pub fn from_exported(exported: &Exported, password: &str) -> Self {
+        let auth_params = &exported.auth_params;
+
+        // 001 and 002 derive their keys from a salt rather than a nonce.
+        let nonce = match (crypto::is_read_only(&auth_params.version), &auth_params.pw_salt) {
+            (true, Some(salt)) => salt.clone(),
+            _ => auth_params.pw_nonce.clone(),
+        };
+
         Self {
             identifier: exported.auth_params.identifier.clone(),
             cost: exported.auth_params.pw_cost,
-            nonce: exported.auth_params.pw_nonce.clone(),
+            nonce,
             password: password.to_string(),
+            version: exported.auth_params.version.clone(),
         }
     }
 
-    pub fn from_defaults(identifier: &str, password: &str) -> Self {
+    /// Create credentials of protocol `version` with a fresh nonce and the version's default cost.
+    pub fn from_defaults(identifier: &str, password: &str, version: &str) -> Self {
         Self {
             identifier: identifier.to_string(),
-            cost: 110000,
+            cost: crypto::default_cost(version),
             nonce: crypto::make_nonce(),
             password: password.to_string(),
+            version: version.to_string(),
+        }
+    }
+}
+
+impl NoteFlags {
+    /// Remove the flags from the content fields of a note.
+    fn take(extra: &mut Map<String, Value>) -> Self {
+        let mut flags = NoteFlags {
+            trashed: extra.remove("trashed").and_then(|value| value.as_bool()).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        let app_data = extra
+            .get_mut("appData")
+            .and_then(|app_data| app_data.get_mut(APP_DATA_DOMAIN))
+            .and_then(|domain| domain.as_object_mut());
+
+        if let Some(domain) = app_data {
+            let mut take = |name: &str| domain.remove(name).and_then(|value| value.as_bool()).unwrap_or_default();
+            flags.pinned = take("pinned");
+            flags.archived = take("archived");
+            flags.locked = take("locked");
+        }
+
+        flags
+    }
+
+    /// Add the flags that are set to the content fields of a note.
+    fn put(&self, extra: &mut Map<String, Value>) {
+        if self.trashed {
+            extra.insert("trashed".to_string(), Value::Bool(true));
+        }
+
+        let set = [("pinned", self.pinned), ("archived", self.archived), ("locked", self.locked)];
+
+        if !set.iter().any(|(_, value)| *value) {
+            return;
+        }
+
+        let app_data = extra
+            .entry("appData")
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .map(|app_data| app_data.entry(APP_DATA_DOMAIN).or_insert_with(|| Value::Object(Map::new())))
+            .and_then(|domain| domain.as_object_mut());
+
+        if let Some(domain) = app_data {
+            for (name, value) in set.iter().filter(|(_, value)| *value) {
+                domain.insert(name.to_string(), Value::Bool(*value));
+            }
         }
     }
 }
 
 impl Note {
     fn encrypt(&self, crypto: &crypto::Crypto) -> Result<Envelope> {
+        let mut extra = self.extra.clone();
+        self.flags.put(&mut extra);
+
         let content = NoteContent {
             title: Some(self.title.clone()),
             text: self.text.clone(),
+            extra,
         };
 
         let to_encrypt = serde_json::to_string(&content)?;
@@ -217,6 +398,8 @@ impl Note {
             content: Some(encrypted.content),
             content_type: "Note".to_owned(),
             enc_item_key: Some(encrypted.enc_item_key),
+            items_key_id: encrypted.items_key_id,
+            auth_hash: None,
             created_at: self.created_at,
             updated_at: self.updated_at,
             deleted: Some(false),
@@ -225,29 +408,118 @@ impl Note {
 
     fn decrypt(crypto: &crypto::Crypto, item: &Envelope) -> Result<Item> {
         let decrypted = crypto.decrypt(item)?;
-        let content = serde_json::from_str::<NoteContent>(&decrypted)?;
+        let mut content = serde_json::from_str::<NoteContent>(&decrypted)?;
+        let flags = NoteFlags::take(&mut content.extra);
 
         Ok(Item::Note(Note {
-            title: content.title.unwrap_or("".to_string()),
+            title: content.title.unwrap_or_default(),
             text: content.text,
             created_at: item.created_at,
             updated_at: item.updated_at,
             uuid: item.uuid,
+            flags,
+            extra: content.extra,
         }))
     }
 }
 
+impl Reference {
+    pub fn note(uuid: Uuid) -> Self {
+        Self {
+            uuid,
+            content_type: "Note".to_string(),
+            reference_type: None,
+        }
+    }
+
+    pub fn parent_tag(uuid: Uuid) -> Self {
+        Self {
+            uuid,
+            content_type: "Tag".to_string(),
+            reference_type: Some(TAG_TO_PARENT_TAG.to_string()),
+        }
+    }
+
+    fn is_parent_tag(&self) -> bool {
+        self.reference_type.as_deref() == Some(TAG_TO_PARENT_TAG)
+    }
+}
+
 impl Tag {
+    /// Create a new tag without any notes.
+    pub fn new(title: &str) -> Self {
+        let now = Utc::now();
+
+        Self {
+            title: title.to_string(),
+            references: Vec::new(),
+            created_at: now,
+            updated_at: now,
+            uuid: Uuid::new_v4(),
+            extra: Map::new(),
+        }
+    }
+
+    /// Uuids of the tagged notes.
+    pub fn notes(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.references
+            .iter()
+            .filter(|reference| reference.content_type == "Note")
+            .map(|reference| reference.uuid)
+    }
+
+    pub fn has_note(&self, uuid: &Uuid) -> bool {
+        self.notes().any(|note| note == *uuid)
+    }
+
+    /// Tag the note. Returns false if it was tagged already.
+    pub fn add_note(&mut self, uuid: Uuid) -> bool {
+        if self.has_note(&uuid) {
+            return false;
+        }
+
+        self.references.push(Reference::note(uuid));
+        true
+    }
+
+    /// Untag the note. Returns false if it was not tagged.
+    pub fn remove_note(&mut self, uuid: &Uuid) -> bool {
+        let count = self.references.len();
+        self.references.retain(|reference| reference.content_type != "Note" || reference.uuid != *uuid);
+        self.references.len() != count
+    }
+
+    /// Make references to `old` refer to `new` instead. Returns false if there were none.
+    pub fn replace_reference(&mut self, old: &Uuid, new: Uuid) -> bool {
+        let mut replaced = false;
+
+        for reference in self.references.iter_mut().filter(|reference| reference.uuid == *old) {
+            reference.uuid = new;
+            replaced = true;
+        }
+
+        replaced
+    }
+
+    /// Parent of a nested tag.
+    pub fn parent(&self) -> Option<Uuid> {
+        self.references.iter().find(|reference| reference.is_parent_tag()).map(|reference| reference.uuid)
+    }
+
+    /// Nest the tag below `parent` or move it to the top level.
+    pub fn set_parent(&mut self, parent: Option<Uuid>) {
+        self.references.retain(|reference| !reference.is_parent_tag());
+
+        if let Some(parent) = parent {
+            self.references.push(Reference::parent_tag(parent));
+        }
+    }
+
     fn encrypt(&self, crypto: &crypto::Crypto) -> Result<Envelope> {
         let content = TagContent {
             title: self.title.clone(),
-            references: self.references
-                .iter()
-                .map(|uuid| Reference {
-                    uuid: uuid.clone(),
-                    content_type: "Note".to_string(),
-                })
-                .collect::<_>()
+            references: self.references.clone(),
+            extra: self.extra.clone(),
         };
 
         let to_encrypt = serde_json::to_string(&content)?;
@@ -256,8 +528,10 @@ impl Tag {
         Ok(Envelope {
             uuid: self.uuid,
             content: Some(encrypted.content),
-            content_type: "Note".to_owned(),
+            content_type: "Tag".to_owned(),
             enc_item_key: Some(encrypted.enc_item_key),
+            items_key_id: encrypted.items_key_id,
+            auth_hash: None,
             created_at: self.created_at,
             updated_at: self.updated_at,
             deleted: Some(false),
@@ -267,14 +541,92 @@ impl Tag {
     fn decrypt(crypto: &crypto::Crypto, item: &Envelope) -> Result<Item> {
         let decrypted = crypto.decrypt(item)?;
         let content = serde_json::from_str::<TagContent>(&decrypted)?;
-        let references = content.references
-            .iter()
-            .map(|reference| reference.uuid)
-            .collect::<_>();
 
         Ok(Item::Tag(Tag {
             title: content.title,
-            references: references,
+            references: content.references,
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+            uuid: item.uuid,
+            extra: content.extra,
+        }))
+    }
+}
+
+impl ItemsKey {
+    /// Create a new random items key for the given protocol version.
+    pub fn new(version: &str, is_default: bool) -> Self {
+        let now = Utc::now();
+
+        Self {
+            key: crypto::make_key(),
+            version: version.to_string(),
+            is_default,
+            created_at: now,
+            updated_at: now,
+            uuid: Uuid::new_v4(),
+        }
+    }
+
+    fn encrypt(&self, crypto: &crypto::Crypto) -> Result<Envelope> {
+        let content = ItemsKeyContent {
+            items_key: self.key.clone(),
+            version: self.version.clone(),
+            is_default: self.is_default,
+        };
+
+        let to_encrypt = serde_json::to_string(&content)?;
+        let encrypted = crypto.encrypt_with_root_key(&to_encrypt, &self.uuid)?;
+
+        Ok(Envelope {
+            uuid: self.uuid,
+            content: Some(encrypted.content),
+            content_type: "SN|ItemsKey".to_owned(),
+            enc_item_key: Some(encrypted.enc_item_key),
+            items_key_id: None,
+            auth_hash: None,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            deleted: Some(false),
+        })
+    }
+
+    fn decrypt(crypto: &crypto::Crypto, item: &Envelope) -> Result<Item> {
+        let decrypted = crypto.decrypt(item)?;
+        let content = serde_json::from_str::<ItemsKeyContent>(&decrypted)?;
+
+        Ok(Item::ItemsKey(ItemsKey {
+            key: content.items_key,
+            version: content.version,
+            is_default: content.is_default,
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+            uuid: item.uuid,
+        }))
+    }
+}
+
+impl Other {
+    fn encrypt(&self, crypto: &crypto::Crypto) -> Result<Envelope> {
+        let encrypted = crypto.encrypt(&self.content, &self.uuid)?;
+
+        Ok(Envelope {
+            uuid: self.uuid,
+            content: Some(encrypted.content),
+            content_type: self.content_type.clone(),
+            enc_item_key: Some(encrypted.enc_item_key),
+            items_key_id: encrypted.items_key_id,
+            auth_hash: None,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            deleted: Some(false),
+        })
+    }
+
+    fn decrypt(crypto: &crypto::Crypto, item: &Envelope) -> Result<Item> {
+        Ok(Item::Other(Other {
+            content_type: item.content_type.clone(),
+            content: crypto.decrypt(item)?,
             created_at: item.created_at,
             updated_at: item.updated_at,
             uuid: item.uuid,
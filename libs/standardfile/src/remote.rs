@@ -1,29 +1,103 @@
 use super::{Credentials, Envelope, crypto::Crypto};
 use anyhow::{anyhow, Result};
-use reqwest::{StatusCode, blocking::Response, header::{HeaderMap, HeaderValue, CONTENT_TYPE}};
-use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+use reqwest::{StatusCode, blocking::{RequestBuilder, Response}, header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER}};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
 use uuid::Uuid;
 
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
+/// API revision that hands out sessions and reports sync conflicts in a separate `conflicts`
+/// array.
+static API_VERSION: &str = "20200115";
+
+/// Status code of requests made with an expired access token.
+const EXPIRED_ACCESS_TOKEN: u16 = 498;
+
 #[derive(Deserialize)]
 pub struct AuthParamsResponse {
+    #[serde(default)]
     pub pw_cost: u32,
     pub pw_nonce: String,
     pub version: String,
 }
 
+/// Error body of a failed request. Older servers send a list of messages, newer ones a single
+/// error with a machine-readable tag.
 #[derive(Deserialize)]
-struct User {
-    pub uuid: Uuid,
-    pub email: String,
+struct ErrorResponse {
+    #[serde(default)]
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub error: Option<ErrorBody>,
 }
 
 #[derive(Deserialize)]
-struct ErrorResponse {
-    pub errors: Vec<String>,
+struct ErrorBody {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub payload: Option<ErrorPayload>,
+}
+
+#[derive(Deserialize)]
+struct ErrorPayload {
+    #[serde(default)]
+    pub mfa_key: Option<String>,
+}
+
+/// Errors of requests to the remote. Functions of `Client` return these wrapped in an
+/// `anyhow::Error`, use `downcast_ref` to tell them apart.
+#[derive(Error, Debug)]
+pub enum RemoteError {
+    #[error("invalid credentials: {0}")]
+    InvalidCredentials(String),
+    #[error(transparent)]
+    MfaRequired(MfaRequired),
+    #[error("session expired")]
+    ExpiredToken,
+    #[error("too many requests")]
+    RateLimited {
+        /// Time the server asks us to wait before trying again.
+        retry_after: Option<Duration>,
+    },
+    #[error("server responded with {status}: {message}")]
+    Server {
+        status: StatusCode,
+        message: String,
+    },
+    #[error("invalid response: {0}")]
+    InvalidJson(#[source] serde_json::Error),
+    #[error("network error: {0}")]
+    Network(#[source] reqwest::Error),
+}
+
+/// Sign-in of an account with two-factor authentication requires a code. Retry with
+/// `Client::new_sign_in_with_mfa` and the code under `mfa_key`.
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct MfaRequired {
+    /// Name under which the server expects the code.
+    pub mfa_key: String,
+    pub message: String,
+}
+
+/// Answer to an `MfaRequired` challenge.
+#[derive(Clone, Debug)]
+pub struct MfaCode {
+    pub key: String,
+    pub code: String,
 }
 
 #[derive(Serialize)]
 struct RegistrationRequest {
+    pub api: &'static str,
     pub email: String,
     pub password: String,
     pub pw_cost: u32,
@@ -33,131 +107,640 @@ struct RegistrationRequest {
 
 #[derive(Serialize)]
 struct SignInRequest {
+    pub api: &'static str,
     pub email: String,
     pub password: String,
+    #[serde(flatten)]
+    pub mfa: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct ChangePasswordRequest {
+    pub api: &'static str,
+    pub identifier: String,
+    pub current_password: String,
+    pub new_password: String,
+    pub pw_cost: u32,
+    pub pw_nonce: String,
+    pub version: String,
 }
 
+#[derive(Serialize)]
+struct RefreshRequest {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct RevokeSessionRequest {
+    pub uuid: Uuid,
+}
+
+/// Response to requests that start or renew a session. Servers predating sessions return a
+/// single `token` instead.
 #[derive(Deserialize)]
-struct SignInResponse {
-    pub user: User,
-    pub token: String,
+struct SessionResponse {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub session: Option<Session>,
+}
+
+/// Tokens authenticating a client. Sessions of servers predating the session API consist of a
+/// long-lived access token only.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Session {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Expiration of the access token in milliseconds since the epoch.
+    #[serde(default)]
+    pub access_expiration: Option<i64>,
+    /// Expiration of the refresh token in milliseconds since the epoch.
+    #[serde(default)]
+    pub refresh_expiration: Option<i64>,
+}
+
+impl Session {
+    /// True if the session can be neither used nor refreshed anymore.
+    pub fn is_expired(&self) -> bool {
+        let now = Utc::now().timestamp_millis();
+
+        match (&self.refresh_token, self.refresh_expiration, self.access_expiration) {
+            (Some(_), Some(expiration), _) => expiration <= now,
+            (None, _, Some(expiration)) => expiration <= now,
+            _ => false,
+        }
+    }
+}
+
+/// A session of the account, possibly on another device.
+#[derive(Deserialize, Debug)]
+pub struct SessionInfo {
+    pub uuid: Uuid,
+    #[serde(default)]
+    pub api_version: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub device_info: String,
+    /// Set for the session of this client.
+    #[serde(default)]
+    pub current: bool,
 }
 
 #[derive(Serialize)]
 struct SyncRequest {
+    pub api: &'static str,
     pub items: Vec<Envelope>,
     pub sync_token: Option<String>,
     pub cursor_token: Option<String>,
 }
 
+/// Reason why the server refused to save an item.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictType {
+    /// The item has been changed on the server since we retrieved it.
+    SyncConflict,
+    /// The uuid is already used by an item of another user.
+    UuidConflict,
+    UuidError,
+    ContentError,
+    ReadonlyError,
+    #[serde(other)]
+    Unknown,
+}
+
+/// An item the server did not save.
+#[derive(Deserialize, Debug)]
+pub struct Conflict {
+    #[serde(rename = "type")]
+    pub kind: ConflictType,
+    /// Current version of the item on the server.
+    pub server_item: Option<Envelope>,
+    /// The item we tried to save.
+    pub unsaved_item: Option<Envelope>,
+}
+
+impl Conflict {
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.server_item.as_ref().or(self.unsaved_item.as_ref()).map(|item| item.uuid)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct UnsavedError {
+    pub tag: ConflictType,
+}
+
+/// Conflict as reported by servers predating the `conflicts` array.
+#[derive(Deserialize, Debug)]
+struct Unsaved {
+    pub item: Envelope,
+    pub error: UnsavedError,
+}
+
 #[derive(Deserialize, Debug)]
 struct SyncResponse {
+    #[serde(default)]
     pub retrieved_items: Vec<Envelope>,
+    #[serde(default)]
     pub saved_items: Vec<Envelope>,
-    pub unsaved: Option<Vec<Envelope>>,
+    #[serde(default)]
+    pub unsaved: Vec<Unsaved>,
+    #[serde(default)]
+    pub conflicts: Vec<Conflict>,
     pub sync_token: Option<String>,
     pub cursor_token: Option<String>,
 }
 
+/// Outcome of a sync.
+#[derive(Debug, Default)]
+pub struct SyncResult {
+    /// Items changed on the server since the last sync.
+    pub retrieved: Vec<Envelope>,
+    /// Items the server saved, carrying the server's timestamps.
+    pub saved: Vec<Envelope>,
+    /// Items the server refused to save.
+    pub conflicts: Vec<Conflict>,
+    /// Token to continue from with the next sync.
+    pub sync_token: Option<String>,
+}
+
+impl SyncResult {
+    /// Current version of a conflicting item on the server. Older servers do not report it with
+    /// the conflict but among the retrieved items.
+    pub fn server_item<'a>(&'a self, conflict: &'a Conflict) -> Option<&'a Envelope> {
+        conflict.server_item.as_ref().or_else(|| {
+            let uuid = conflict.uuid()?;
+            self.retrieved.iter().find(|item| item.uuid == uuid)
+        })
+    }
+}
+
+/// A version of an item kept by the server. The content is fetched separately with
+/// `Client::revision`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Revision {
+    pub uuid: Uuid,
+    /// Item this is a version of.
+    pub item_uuid: Uuid,
+    pub content_type: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Revision including its encrypted content.
+#[derive(Deserialize, Debug)]
+struct RevisionItem {
+    pub item_uuid: Uuid,
+    pub content: Option<String>,
+    pub content_type: String,
+    pub enc_item_key: Option<String>,
+    #[serde(default)]
+    pub items_key_id: Option<Uuid>,
+    #[serde(default)]
+    pub auth_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<RevisionItem> for Envelope {
+    /// The envelope carries the uuid of the item, which the content is authenticated with.
+    fn from(revision: RevisionItem) -> Self {
+        Envelope {
+            uuid: revision.item_uuid,
+            content: revision.content,
+            content_type: revision.content_type,
+            enc_item_key: revision.enc_item_key,
+            items_key_id: revision.items_key_id,
+            auth_hash: revision.auth_hash,
+            created_at: revision.created_at,
+            updated_at: revision.updated_at,
+            deleted: None,
+        }
+    }
+}
+
+/// Newer servers wrap revisions in an object, older ones return them as they are.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RevisionsResponse {
+    Wrapped { revisions: Vec<Revision> },
+    Plain(Vec<Revision>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RevisionResponse {
+    Wrapped { revision: RevisionItem },
+    Plain(RevisionItem),
+}
+
 pub struct Client {
     host: String,
     pub credentials: Credentials,
     client: reqwest::blocking::Client,
-    auth_token: String,
+    session: Session,
     sync_token: Option<String>,
 }
 
-fn get_token_from_signin_response(response: Response) -> Result<String> {
-    match response.status() {
-        StatusCode::OK => {
-            let response = response.json::<SignInResponse>()?;
-            Ok(response.token)
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Turn the status and body of an unsuccessful response into an error.
+fn error_from_body(status: StatusCode, retry_after: Option<Duration>, body: reqwest::Result<String>) -> RemoteError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return RemoteError::RateLimited { retry_after };
+    }
+
+    if status.as_u16() == EXPIRED_ACCESS_TOKEN {
+        return RemoteError::ExpiredToken;
+    }
+
+    let body = match body {
+        Ok(body) => body,
+        Err(err) => return RemoteError::Network(err),
+    };
+
+    let (message, tag, mfa_key) = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(ErrorResponse { error: Some(error), .. }) => {
+            (error.message, error.tag, error.payload.and_then(|payload| payload.mfa_key))
         }
-        _ => {
-            let response = response.json::<ErrorResponse>()?;
-            Err(anyhow!("{}", response.errors[0]))
+        Ok(ErrorResponse { errors, .. }) => (errors.into_iter().next().unwrap_or_default(), None, None),
+        Err(_) => (String::new(), None, None),
+    };
+
+    let message = if message.is_empty() {
+        status.canonical_reason().unwrap_or("unknown error").to_string()
+    } else {
+        message
+    };
+
+    match (tag.as_deref(), mfa_key) {
+        (Some("mfa-required"), Some(mfa_key)) => RemoteError::MfaRequired(MfaRequired { mfa_key, message }),
+        _ if status == StatusCode::UNAUTHORIZED => RemoteError::InvalidCredentials(message),
+        _ => RemoteError::Server { status, message },
+    }
+}
+
+fn error_from_response(response: Response) -> RemoteError {
+    let status = response.status();
+    let retry_after = retry_after(response.headers());
+    error_from_body(status, retry_after, response.text())
+}
+
+fn parse_body<T: DeserializeOwned>(body: reqwest::Result<String>) -> Result<T, RemoteError> {
+    let body = body.map_err(RemoteError::Network)?;
+    serde_json::from_str(&body).map_err(RemoteError::InvalidJson)
+}
+
+/// Send a request that does not need a session.
+fn send_request(request: RequestBuilder) -> Result<Response, RemoteError> {
+    request.send().map_err(RemoteError::Network)
+}
+
+/// Decode the body of a successful response or turn an unsuccessful one into an error.
+fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T, RemoteError> {
+    if !response.status().is_success() {
+        return Err(error_from_response(response));
+    }
+
+    parse_body(response.text())
+}
+
+fn session_from_response(response: SessionResponse) -> Result<Session> {
+    match (response.session, response.token) {
+        (Some(session), _) => Ok(session),
+        (None, Some(token)) => Ok(Session {
+            access_token: token,
+            refresh_token: None,
+            access_expiration: None,
+            refresh_expiration: None,
+        }),
+        (None, None) => Err(anyhow!("Server did not start a session")),
+    }
+}
+
+fn get_session_from_response(response: Response) -> Result<Session> {
+    session_from_response(parse_response::<SessionResponse>(response)?)
+}
+
+fn registration_request(credentials: &Credentials) -> Result<RegistrationRequest> {
+    Ok(RegistrationRequest {
+        api: API_VERSION,
+        email: credentials.identifier.to_string(),
+        password: Crypto::new(credentials)?.password(),
+        pw_cost: credentials.cost,
+        pw_nonce: credentials.nonce.clone(),
+        version: credentials.version.clone(),
+    })
+}
+
+/// Query parameters answering a two-factor challenge. These must be sent with both the auth
+/// params and the sign-in request.
+fn mfa_params(mfa: Option<&MfaCode>) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+
+    if let Some(mfa) = mfa {
+        params.insert(mfa.key.clone(), mfa.code.clone());
+    }
+
+    params
+}
+
+/// Apply the server's encryption parameters to `credentials` and build the sign-in request.
+fn sign_in_request(credentials: &Credentials, auth_params: AuthParamsResponse, mfa: HashMap<String, String>) -> Result<(Credentials, SignInRequest)> {
+    let mut credentials = credentials.clone();
+    credentials.cost = auth_params.pw_cost;
+    credentials.nonce = auth_params.pw_nonce;
+    credentials.version = auth_params.version;
+
+    let request = SignInRequest {
+        api: API_VERSION,
+        email: credentials.identifier.clone(),
+        password: Crypto::new(&credentials)?.password(),
+        mfa,
+    };
+
+    Ok((credentials, request))
+}
+
+fn refresh_request(session: &Session) -> Result<RefreshRequest> {
+    let refresh_token = session.refresh_token.clone().ok_or(RemoteError::ExpiredToken)?;
+
+    Ok(RefreshRequest {
+        access_token: session.access_token.clone(),
+        refresh_token,
+    })
+}
+
+/// A rejected refresh token cannot be used anymore, the account has to sign in again.
+fn is_refresh_rejected(status: StatusCode) -> bool {
+    status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_unauthorized(status: StatusCode) -> bool {
+    status.as_u16() == EXPIRED_ACCESS_TOKEN || status == StatusCode::UNAUTHORIZED
+}
+
+/// Whether a request answered with `status` must be sent again after refreshing `session`. If it
+/// is still unauthorized afterwards, e.g. because of a wrong password, the response's own error
+/// applies rather than `RemoteError::ExpiredToken`.
+fn needs_refresh(session: &Session, status: StatusCode) -> bool {
+    session.refresh_token.is_some() && is_unauthorized(status)
+}
+
+impl SyncResponse {
+    /// Add this page to `result`. Returns the cursor of the next page, if there is one.
+    fn merge_into(self, result: &mut SyncResult) -> Option<String> {
+        result.retrieved.extend(self.retrieved_items);
+        result.saved.extend(self.saved_items);
+        result.conflicts.extend(self.conflicts);
+        result.conflicts.extend(self.unsaved.into_iter().map(|unsaved| Conflict {
+            kind: unsaved.error.tag,
+            server_item: None,
+            unsaved_item: Some(unsaved.item),
+        }));
+
+        if self.sync_token.is_some() {
+            result.sync_token = self.sync_token;
         }
+
+        self.cursor_token.filter(|token| !token.is_empty())
     }
 }
 
 impl Client {
     /// Create client by registering a new user
     pub fn new_register(host: &str, credentials: Credentials) -> Result<Client> {
-        let crypto = Crypto::new(&credentials)?;
-        let encoded_pw = crypto.password();
-
-        let request = RegistrationRequest {
-            email: credentials.identifier.to_string(),
-            password: encoded_pw,
-            pw_cost: credentials.cost,
-            pw_nonce: credentials.nonce.clone(),
-            version: "003".to_string(),
-        };
-
+        let request = registration_request(&credentials)?;
         let url = format!("{}/auth", host);
         let client = reqwest::blocking::Client::new();
-        let response = client.post(&url).json(&request).send()?;
+        let response = send_request(client.post(&url).json(&request))?;
 
         Ok(Self {
             host: host.to_string(),
-            credentials: credentials,
-            client: client,
-            auth_token: get_token_from_signin_response(response)?,
+            credentials,
+            client,
+            session: get_session_from_response(response)?,
             sync_token: None,
         })
     }
 
-    /// Create client by signing in.
-    pub fn new_sign_in(host: &str, credentials: &Credentials) -> Result<Client> {
-        let client = reqwest::blocking::Client::new();
-
-        let url = format!("{}/auth/params?email={}", host, credentials.identifier);
-        let response = client.get(&url).send()?.json::<AuthParamsResponse>()?;
+    /// Create client from a session started earlier, e.g. by a previous run. `credentials` must
+    /// be the ones returned by the client that started the session.
+    pub fn from_session(host: &str, credentials: &Credentials, session: Session) -> Client {
+        Self {
+            host: host.to_string(),
+            credentials: credentials.clone(),
+            client: reqwest::blocking::Client::new(),
+            session,
+            sync_token: None,
+        }
+    }
 
-        let mut credentials = credentials.clone();
-        credentials.cost = response.pw_cost;
-        credentials.nonce = response.pw_nonce;
+    /// Create client by signing in. Fails with `RemoteError::MfaRequired` if the account uses
+    /// two-factor authentication.
+    pub fn new_sign_in(host: &str, credentials: &Credentials) -> Result<Client> {
+        Client::new_sign_in_with_mfa(host, credentials, None)
+    }
 
-        let crypto = Crypto::new(&credentials)?;
-        let encoded_pw = crypto.password();
+    /// Create client by signing in with the code answering an `MfaRequired` challenge.
+    pub fn new_sign_in_with_mfa(host: &str, credentials: &Credentials, mfa: Option<&MfaCode>) -> Result<Client> {
+        let client = reqwest::blocking::Client::new();
+        let params = mfa_params(mfa);
 
-        let request = SignInRequest {
-            email: credentials.identifier.clone(),
-            password: encoded_pw,
-        };
+        let url = format!("{}/auth/params", host);
+        let response = send_request(client.get(&url).query(&[("email", &credentials.identifier)]).query(&params))?;
+        let (credentials, request) = sign_in_request(credentials, parse_response(response)?, params)?;
 
         let url = format!("{}/auth/sign_in", host);
-        let response = client.post(&url).json(&request).send()?;
+        let response = send_request(client.post(&url).json(&request))?;
 
         Ok(Self {
             host: host.to_string(),
-            credentials: credentials,
-            client: client,
-            auth_token: get_token_from_signin_response(response)?,
+            credentials,
+            client,
+            session: get_session_from_response(response)?,
             sync_token: None,
         })
     }
 
-    pub fn sync(&mut self, items: Vec<Envelope>) -> Result<Vec<Envelope>> {
+    /// Tokens of the current session. These change whenever the session is refreshed.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Send an authenticated request built by `request`. If the access token has expired, the
+    /// session is refreshed and the request sent again. Fails with `RemoteError::ExpiredToken`
+    /// if the refresh is rejected, a request that is unauthorized nonetheless, e.g. because of a
+    /// wrong password, is returned as is.
+    fn send<F>(&mut self, request: F) -> Result<Response>
+    where
+        F: Fn(&reqwest::blocking::Client) -> RequestBuilder,
+    {
+        let response = send_request(request(&self.client).bearer_auth(&self.session.access_token))?;
+
+        if !needs_refresh(&self.session, response.status()) {
+            return Ok(response);
+        }
+
+        self.refresh()?;
+        Ok(send_request(request(&self.client).bearer_auth(&self.session.access_token))?)
+    }
+
+    /// Renew the access token with the refresh token.
+    pub fn refresh(&mut self) -> Result<()> {
+        let request = refresh_request(&self.session)?;
+        let url = format!("{}/session/refresh", &self.host);
+        let response = send_request(self.client.post(&url).json(&request))?;
+
+        if is_refresh_rejected(response.status()) {
+            return Err(RemoteError::ExpiredToken.into());
+        }
+
+        self.session = get_session_from_response(response)?;
+        Ok(())
+    }
+
+    /// List all sessions of the account.
+    pub fn sessions(&mut self) -> Result<Vec<SessionInfo>> {
+        let url = format!("{}/sessions", &self.host);
+        let response = self.send(|client| client.get(&url))?;
+
+        Ok(parse_response::<Vec<SessionInfo>>(response)?)
+    }
+
+    /// End another session of the account.
+    pub fn revoke_session(&mut self, uuid: &Uuid) -> Result<()> {
+        let url = format!("{}/session", &self.host);
+        let request = RevokeSessionRequest { uuid: *uuid };
+        let response = self.send(|client| client.delete(&url).json(&request))?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response).into());
+        }
+
+        Ok(())
+    }
+
+    /// End the session of this client.
+    pub fn sign_out(mut self) -> Result<()> {
+        let url = format!("{}/auth/sign_out", &self.host);
+        let response = self.send(|client| client.post(&url))?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response).into());
+        }
+
+        Ok(())
+    }
+
+    /// Token marking the point up to which items have been retrieved.
+    pub fn sync_token(&self) -> Option<&str> {
+        self.sync_token.as_deref()
+    }
+
+    /// Continue syncing from a previously stored token, only items changed since then are
+    /// retrieved.
+    pub fn set_sync_token(&mut self, token: Option<String>) {
+        self.sync_token = token;
+    }
+
+    /// Upload `items` and retrieve all items changed since the last sync.
+    pub fn sync(&mut self, items: Vec<Envelope>) -> Result<SyncResult> {
+        self.sync_with_progress(items, |_| {})
+    }
+
+    /// Same as `sync` but follows the server's cursor until all pages have been retrieved,
+    /// calling `progress` with the number of items retrieved so far after each page.
+    pub fn sync_with_progress<F: FnMut(usize)>(&mut self, items: Vec<Envelope>, mut progress: F) -> Result<SyncResult> {
         let url = format!("{}/items/sync", &self.host);
-        let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let mut result = SyncResult { sync_token: self.sync_token.clone(), ..Default::default() };
+        let mut items = items;
+        let mut cursor_token = None;
+
+        loop {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+            // Local items are only uploaded with the first page, subsequent requests merely
+            // continue where the cursor left off.
+            let sync_request = SyncRequest {
+                api: API_VERSION,
+                items: std::mem::take(&mut items),
+                sync_token: result.sync_token.clone(),
+                cursor_token: cursor_token.take(),
+            };
+
+            let body = serde_json::to_string(&sync_request)?;
+            let response = self.send(|client| client.post(&url).headers(headers.clone()).body(body.clone()))?;
+
+            cursor_token = parse_response::<SyncResponse>(response)?.merge_into(&mut result);
+            self.sync_token = result.sync_token.clone();
+            progress(result.retrieved.len());
+
+            if cursor_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
 
-        let sync_request = SyncRequest {
-            items: items,
-            sync_token: self.sync_token.clone(),
-            cursor_token: None,
+    /// List the versions the server kept of the item `uuid`, newest first.
+    pub fn revisions(&mut self, uuid: &Uuid) -> Result<Vec<Revision>> {
+        let url = format!("{}/items/{}/revisions", &self.host, uuid);
+        let response = self.send(|client| client.get(&url))?;
+
+        let mut revisions = match parse_response::<RevisionsResponse>(response)? {
+            RevisionsResponse::Wrapped { revisions } | RevisionsResponse::Plain(revisions) => revisions,
+        };
+
+        revisions.sort_by_key(|revision| std::cmp::Reverse(revision.created_at));
+        Ok(revisions)
+    }
+
+    /// Fetch the revision `revision` of the item `uuid`. The returned envelope has the item's
+    /// uuid and is decrypted with `Crypto` like any other item.
+    pub fn revision(&mut self, uuid: &Uuid, revision: &Uuid) -> Result<Envelope> {
+        let url = format!("{}/items/{}/revisions/{}", &self.host, uuid, revision);
+        let response = self.send(|client| client.get(&url))?;
+
+        match parse_response::<RevisionResponse>(response)? {
+            RevisionResponse::Wrapped { revision } | RevisionResponse::Plain(revision) => Ok(revision.into()),
+        }
+    }
+
+    /// Change the password on the server to the one in `credentials`. Items must be
+    /// re-encrypted with the new credentials and uploaded with `sync` afterwards because the
+    /// server does not know how to do that.
+    pub fn change_password(&mut self, credentials: &Credentials) -> Result<()> {
+        let current_password = Crypto::new(&self.credentials)?.password();
+        let new_password = Crypto::new(credentials)?.password();
+
+        let request = ChangePasswordRequest {
+            api: API_VERSION,
+            identifier: credentials.identifier.clone(),
+            current_password,
+            new_password,
+            pw_cost: credentials.cost,
+            pw_nonce: credentials.nonce.clone(),
+            version: credentials.version.clone(),
         };
 
-        let response = self.client
-            .post(&url)
-            .headers(headers)
-            .bearer_auth(&self.auth_token)
-            .body(serde_json::to_string(&sync_request)?)
-            .send()?
-            .json::<SyncResponse>()?;
+        let url = format!("{}/auth/change_pw", &self.host);
+        let response = self.send(|client| client.post(&url).json(&request))?;
 
-        self.sync_token = response.sync_token;
-        Ok(response.retrieved_items)
+        self.session = get_session_from_response(response)?;
+        self.credentials = credentials.clone();
+        Ok(())
     }
 }
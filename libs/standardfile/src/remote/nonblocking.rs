@@ -0,0 +1,205 @@
+use super::{
+    error_from_body, is_refresh_rejected, mfa_params, needs_refresh, parse_body,
+    refresh_request, registration_request, retry_after, session_from_response, sign_in_request,
+    MfaCode, RemoteError, Session, SessionResponse, SyncRequest, SyncResponse, SyncResult,
+    API_VERSION,
+};
+use crate::{Credentials, Envelope};
+use anyhow::Result;
+use reqwest::{RequestBuilder, Response, header::{HeaderMap, HeaderValue, CONTENT_TYPE}};
+use serde::de::DeserializeOwned;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::spawn_blocking;
+
+/// Async version of `remote::Client`, so many accounts can be served from one tokio runtime.
+pub struct Client {
+    host: String,
+    pub credentials: Credentials,
+    client: reqwest::Client,
+    session: Session,
+    sync_token: Option<String>,
+}
+
+/// Create a runtime to drive the client on. reqwest only works on tokio 0.2, so tools built on a
+/// newer tokio have to run the client on a runtime of its own.
+pub fn runtime() -> std::io::Result<Runtime> {
+    Builder::new().basic_scheduler().enable_all().build()
+}
+
+async fn send_request(request: RequestBuilder) -> Result<Response, RemoteError> {
+    request.send().await.map_err(RemoteError::Network)
+}
+
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T, RemoteError> {
+    let status = response.status();
+
+    if !status.is_success() {
+        let retry_after = retry_after(response.headers());
+        return Err(error_from_body(status, retry_after, response.text().await));
+    }
+
+    parse_body(response.text().await)
+}
+
+/// Run `derive` on the blocking pool. Key derivation takes far too long to block the executor,
+/// especially Argon2 of the 004 scheme.
+async fn derive_keys<T, F>(derive: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_blocking(derive).await?
+}
+
+async fn get_session_from_response(response: Response) -> Result<Session> {
+    session_from_response(parse_response::<SessionResponse>(response).await?)
+}
+
+impl Client {
+    /// Create client by registering a new user
+    pub async fn new_register(host: &str, credentials: Credentials) -> Result<Client> {
+        let request = derive_keys({
+            let credentials = credentials.clone();
+            move || registration_request(&credentials)
+        }).await?;
+
+        let url = format!("{}/auth", host);
+        let client = reqwest::Client::new();
+        let response = send_request(client.post(&url).json(&request)).await?;
+
+        Ok(Self {
+            host: host.to_string(),
+            credentials,
+            client,
+            session: get_session_from_response(response).await?,
+            sync_token: None,
+        })
+    }
+
+    /// Create client from a session started earlier. `credentials` must be the ones returned by
+    /// the client that started the session.
+    pub fn from_session(host: &str, credentials: &Credentials, session: Session) -> Client {
+        Self {
+            host: host.to_string(),
+            credentials: credentials.clone(),
+            client: reqwest::Client::new(),
+            session,
+            sync_token: None,
+        }
+    }
+
+    /// Create client by signing in. Fails with `RemoteError::MfaRequired` if the account uses
+    /// two-factor authentication.
+    pub async fn new_sign_in(host: &str, credentials: &Credentials) -> Result<Client> {
+        Client::new_sign_in_with_mfa(host, credentials, None).await
+    }
+
+    /// Create client by signing in with the code answering an `MfaRequired` challenge.
+    pub async fn new_sign_in_with_mfa(host: &str, credentials: &Credentials, mfa: Option<&MfaCode>) -> Result<Client> {
+        let client = reqwest::Client::new();
+        let params = mfa_params(mfa);
+
+        let url = format!("{}/auth/params", host);
+        let response = send_request(client.get(&url).query(&[("email", &credentials.identifier)]).query(&params)).await?;
+        let auth_params = parse_response(response).await?;
+        let (credentials, request) = derive_keys({
+            let credentials = credentials.clone();
+            move || sign_in_request(&credentials, auth_params, params)
+        }).await?;
+
+        let url = format!("{}/auth/sign_in", host);
+        let response = send_request(client.post(&url).json(&request)).await?;
+
+        Ok(Self {
+            host: host.to_string(),
+            credentials,
+            client,
+            session: get_session_from_response(response).await?,
+            sync_token: None,
+        })
+    }
+
+    /// Tokens of the current session. These change whenever the session is refreshed.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Send an authenticated request built by `request`. If the access token has expired, the
+    /// session is refreshed and the request sent again, see `remote::Client`.
+    async fn send<F>(&mut self, request: F) -> Result<Response>
+    where
+        F: Fn(&reqwest::Client) -> RequestBuilder,
+    {
+        let response = send_request(request(&self.client).bearer_auth(&self.session.access_token)).await?;
+
+        if !needs_refresh(&self.session, response.status()) {
+            return Ok(response);
+        }
+
+        self.refresh().await?;
+        Ok(send_request(request(&self.client).bearer_auth(&self.session.access_token)).await?)
+    }
+
+    /// Renew the access token with the refresh token.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let request = refresh_request(&self.session)?;
+        let url = format!("{}/session/refresh", &self.host);
+        let response = send_request(self.client.post(&url).json(&request)).await?;
+
+        if is_refresh_rejected(response.status()) {
+            return Err(RemoteError::ExpiredToken.into());
+        }
+
+        self.session = get_session_from_response(response).await?;
+        Ok(())
+    }
+
+    /// Token marking the point up to which items have been retrieved.
+    pub fn sync_token(&self) -> Option<&str> {
+        self.sync_token.as_deref()
+    }
+
+    /// Continue syncing from a previously stored token.
+    pub fn set_sync_token(&mut self, token: Option<String>) {
+        self.sync_token = token;
+    }
+
+    /// Upload `items` and retrieve all items changed since the last sync.
+    pub async fn sync(&mut self, items: Vec<Envelope>) -> Result<SyncResult> {
+        self.sync_with_progress(items, |_| {}).await
+    }
+
+    /// Same as `sync` but calls `progress` with the number of items retrieved so far after each
+    /// page.
+    pub async fn sync_with_progress<F: FnMut(usize)>(&mut self, items: Vec<Envelope>, mut progress: F) -> Result<SyncResult> {
+        let url = format!("{}/items/sync", &self.host);
+        let mut result = SyncResult { sync_token: self.sync_token.clone(), ..Default::default() };
+        let mut items = items;
+        let mut cursor_token = None;
+
+        loop {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+            let sync_request = SyncRequest {
+                api: API_VERSION,
+                items: std::mem::take(&mut items),
+                sync_token: result.sync_token.clone(),
+                cursor_token: cursor_token.take(),
+            };
+
+            let body = serde_json::to_string(&sync_request)?;
+            let response = self.send(|client| client.post(&url).headers(headers.clone()).body(body.clone())).await?;
+
+            cursor_token = parse_response::<SyncResponse>(response).await?.merge_into(&mut result);
+            self.sync_token = result.sync_token.clone();
+            progress(result.retrieved.len());
+
+            if cursor_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+}
@@ -21,6 +21,9 @@ fn main() {
     println!("cargo:rerun-if-changed=data/resources/css/base.css");
     println!("cargo:rerun-if-changed=data/resources/ui/about.ui");
     println!("cargo:rerun-if-changed=data/resources/ui/import.ui");
+    println!("cargo:rerun-if-changed=data/resources/ui/mfa.ui");
+    println!("cargo:rerun-if-changed=data/resources/ui/password.ui");
+    println!("cargo:rerun-if-changed=data/resources/ui/revisions.ui");
     println!("cargo:rerun-if-changed=data/resources/ui/shortcuts.ui");
     println!("cargo:rerun-if-changed=data/resources/ui/window.ui");
 }
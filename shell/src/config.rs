@@ -23,6 +23,8 @@ struct Identity {
     pub nonce: String,
     pub cost: u32,
     pub server: Option<String>,
+    #[serde(default = "default_version")]
+    pub version: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,12 +32,26 @@ struct Root {
     pub current: String,
     pub identities: Vec<Identity>,
     pub geometry: Option<Geometry>,
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval: u32,
 }
 
 pub struct Config {
     identifier: Option<String>,
     identities: HashMap<String, Identity>,
     pub geometry: Option<Geometry>,
+
+    /// Seconds between pulling remote changes, zero disables pulling.
+    pub sync_interval: u32,
+}
+
+fn default_sync_interval() -> u32 {
+    60
+}
+
+/// Identities written before the version was recorded all used the 003 scheme.
+fn default_version() -> String {
+    "003".to_string()
 }
 
 fn get_path() -> Result<PathBuf> {
@@ -59,6 +75,7 @@ impl Config {
                 identifier: Some(root.current.clone()),
                 identities: HashMap::new(),
                 geometry: root.geometry,
+                sync_interval: root.sync_interval,
             };
 
             for identity in root.identities {
@@ -72,6 +89,7 @@ impl Config {
                 identifier: None,
                 identities: HashMap::new(),
                 geometry: None,
+                sync_interval: default_sync_interval(),
             })
         }
     }
@@ -88,7 +106,8 @@ impl Config {
             identifier: credentials.identifier.clone(),
             nonce: credentials.nonce.clone(),
             cost: credentials.cost,
-            server: server,
+            server,
+            version: credentials.version.clone(),
         };
 
         self.add_identity(identity);
@@ -115,6 +134,7 @@ impl Config {
             identifier: identity.identifier.clone(),
             cost: identity.cost,
             nonce: identity.nonce.clone(),
+            version: identity.version.clone(),
         })
     }
 
@@ -124,13 +144,12 @@ impl Config {
 
         self.identities
             .get(identifier)
-            .map_or(None, |identity| identity.server.as_ref())
-            .map_or(None, |server| Some(server.clone()))
+            .and_then(|identity| identity.server.clone())
     }
 
     /// Get existing identifiers.
     pub fn identifiers(&self) -> Vec<String> {
-        self.identities.keys().map(|s| s.clone()).collect()
+        self.identities.keys().cloned().collect()
     }
 
     pub fn identifier(&self) -> Option<&String> {
@@ -147,26 +166,24 @@ impl Config {
             create_dir_all(path.parent().unwrap())?;
         }
 
-        let geometry = match &self.geometry {
-            Some(geometry) => Some(Geometry {
-                width: geometry.width,
-                height: geometry.height,
-                x: geometry.x,
-                y: geometry.y,
-                maximized: geometry.maximized,
-            }),
-            None => None,
-        };
+        let geometry = self.geometry.as_ref().map(|geometry| Geometry {
+            width: geometry.width,
+            height: geometry.height,
+            x: geometry.x,
+            y: geometry.y,
+            maximized: geometry.maximized,
+        });
 
         let identities = self.identities
             .values()
-            .map(|identity| identity.clone())
+            .cloned()
             .collect();
 
         let root = Root {
             current: identity.identifier.clone(),
-            identities: identities,
-            geometry: geometry,
+            identities,
+            geometry,
+            sync_interval: self.sync_interval,
         };
 
         fs::write(path, toml::to_string(&root)?)?;
@@ -4,6 +4,9 @@ pub static APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub static ABOUT_UI: &str = "/net/bloerg/Iridium/data/resources/ui/about.ui";
 pub static IMPORT_UI: &str = "/net/bloerg/Iridium/data/resources/ui/import.ui";
+pub static MFA_UI: &str = "/net/bloerg/Iridium/data/resources/ui/mfa.ui";
+pub static PASSWORD_UI: &str = "/net/bloerg/Iridium/data/resources/ui/password.ui";
+pub static REVISIONS_UI: &str = "/net/bloerg/Iridium/data/resources/ui/revisions.ui";
 pub static SHORTCUTS_UI: &str = "/net/bloerg/Iridium/data/resources/ui/shortcuts.ui";
 pub static WINDOW_UI: &str = "/net/bloerg/Iridium/data/resources/ui/window.ui";
 pub static BASE_CSS: &str = "/net/bloerg/Iridium/data/resources/css/base.css";
@@ -1,4 +1,6 @@
 #![forbid(unsafe_code)]
+// `is_some_and` and `is_none_or` are too recent for the toolchains we support.
+#![allow(clippy::unnecessary_map_or)]
 
 #[macro_use]
 extern crate glib;
@@ -9,6 +11,7 @@ mod consts;
 mod secret;
 mod storage;
 mod ui;
+mod worker;
 
 use anyhow::Result;
 use gio::{resources_register, Resource};
@@ -17,7 +20,7 @@ use ui::application::Application;
 
 fn init_resources() -> Result<()> {
     let data: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/resources.gresource"));
-    let gbytes = Bytes::from_static(data.as_ref());
+    let gbytes = Bytes::from_static(data);
     let resource = Resource::from_data(&gbytes)?;
     resources_register(&resource);
 
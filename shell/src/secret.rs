@@ -1,9 +1,17 @@
 use anyhow::{anyhow, Result};
 use secret_service::{EncryptionType, SecretService};
-use standardfile::Credentials;
+use standardfile::{Credentials, remote::Session};
 
-/// Store password in the keyring.
-pub fn store(credentials: &Credentials, server: Option<&str>) -> Result<()> {
+/// Type of the keyring item holding the password currently in use.
+static PASSWORD: &str = "password";
+
+/// Type of the keyring item holding the new password while a password change is in progress.
+static PENDING_PASSWORD: &str = "pending-password";
+
+/// Type of the keyring item holding the tokens of the current session.
+static SESSION: &str = "session";
+
+fn store_secret(kind: &str, identifier: &str, secret: &str, server: Option<&str>) -> Result<()> {
     let service = SecretService::new(EncryptionType::Dh)
         .map_err(|err| anyhow!("Could not instantiate SecretService: {}", err))?;
 
@@ -13,8 +21,8 @@ pub fn store(credentials: &Credentials, server: Option<&str>) -> Result<()> {
 
     let mut props = vec![
         ("service", "iridium"),
-        ("identifier", &credentials.identifier),
-        ("type", "password"),
+        ("identifier", identifier),
+        ("type", kind),
     ];
 
     if let Some(server) = server {
@@ -23,24 +31,25 @@ pub fn store(credentials: &Credentials, server: Option<&str>) -> Result<()> {
 
     collection
         .create_item(
-            &format!("Iridium password for {}", credentials.identifier),
+            &format!("Iridium {} for {}", kind, identifier),
             props,
-            credentials.password.as_bytes(),
+            secret.as_bytes(),
             true,
             "text/plain",
         )
-        .map_err(|err| anyhow!("Could not create password item: {}", err))?;
+        .map_err(|err| anyhow!("Could not create {} item: {}", kind, err))?;
 
     Ok(())
 }
 
-/// Load password for a given identifier.
-pub fn load(identifier: &str, server: &Option<String>) -> Result<String> {
-    let service = SecretService::new(EncryptionType::Dh).unwrap();
+fn load_secret(kind: &str, identifier: &str, server: &Option<String>) -> Result<Option<String>> {
+    let service = SecretService::new(EncryptionType::Dh)
+        .map_err(|err| anyhow!("Could not instantiate SecretService: {}", err))?;
+
     let mut query = vec![
         ("service", "iridium"),
         ("identifier", identifier),
-        ("type", "password"),
+        ("type", kind),
     ];
 
     if let Some(server) = server {
@@ -51,11 +60,78 @@ pub fn load(identifier: &str, server: &Option<String>) -> Result<String> {
         .search_items(query)
         .map_err(|err| anyhow!("Service query failed: {}", err))?;
 
-    Ok(String::from_utf8(
-        items
-            .get(0)
-            .ok_or(anyhow!("Password not found"))?
-            .get_secret()
-            .map_err(|err| anyhow!("Could not get secret for password: {}", err))?,
-    )?)
+    match items.first() {
+        Some(item) => {
+            let secret = item
+                .get_secret()
+                .map_err(|err| anyhow!("Could not get secret for {}: {}", kind, err))?;
+
+            Ok(Some(String::from_utf8(secret)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Store password in the keyring.
+pub fn store(credentials: &Credentials, server: Option<&str>) -> Result<()> {
+    store_secret(PASSWORD, &credentials.identifier, &credentials.password, server)
+}
+
+/// Load password for a given identifier.
+pub fn load(identifier: &str, server: &Option<String>) -> Result<String> {
+    load_secret(PASSWORD, identifier, server)?.ok_or_else(|| anyhow!("Password not found"))
+}
+
+/// Store the new password of a password change that has not been completed yet.
+pub fn store_pending(credentials: &Credentials, server: Option<&str>) -> Result<()> {
+    store_secret(PENDING_PASSWORD, &credentials.identifier, &credentials.password, server)
+}
+
+/// Load the new password of an interrupted password change, if there is one.
+pub fn load_pending(identifier: &str, server: &Option<String>) -> Result<Option<String>> {
+    load_secret(PENDING_PASSWORD, identifier, server)
+}
+
+/// Remove the new password once a password change has been completed or rolled back.
+pub fn clear_pending(identifier: &str) -> Result<()> {
+    delete_secrets(PENDING_PASSWORD, identifier)
+}
+
+/// Store the session so the next start does not need to sign in again.
+pub fn store_session(identifier: &str, session: &Session, server: &str) -> Result<()> {
+    store_secret(SESSION, identifier, &serde_json::to_string(session)?, Some(server))
+}
+
+/// Load the session of a previous run, if there is one.
+pub fn load_session(identifier: &str, server: &Option<String>) -> Result<Option<Session>> {
+    match load_secret(SESSION, identifier, server)? {
+        Some(secret) => Ok(Some(serde_json::from_str(&secret)?)),
+        None => Ok(None),
+    }
+}
+
+/// Forget the session once the remote does not accept it anymore.
+pub fn clear_session(identifier: &str) -> Result<()> {
+    delete_secrets(SESSION, identifier)
+}
+
+fn delete_secrets(kind: &str, identifier: &str) -> Result<()> {
+    let service = SecretService::new(EncryptionType::Dh)
+        .map_err(|err| anyhow!("Could not instantiate SecretService: {}", err))?;
+
+    let query = vec![
+        ("service", "iridium"),
+        ("identifier", identifier),
+        ("type", kind),
+    ];
+
+    let items = service
+        .search_items(query)
+        .map_err(|err| anyhow!("Service query failed: {}", err))?;
+
+    for item in items {
+        item.delete().map_err(|err| anyhow!("Could not delete {}: {}", kind, err))?;
+    }
+
+    Ok(())
 }
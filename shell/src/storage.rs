@@ -1,15 +1,40 @@
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
 use crate::consts::APP_DOMAIN;
-use standardfile::{AuthParams, remote, CryptoError, Envelope, Exported, Item, Note, Credentials, crypto::Crypto};
+use standardfile::{AuthParams, remote, remote::{ConflictType, RemoteError, SyncResult}, CryptoError, Envelope, Exported, Item, ItemsKey, Note, NoteFlags, Tag, Credentials, crypto, crypto::Crypto};
 use data_encoding::HEXLOWER;
 use directories::BaseDirs;
 use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::cmp;
 use std::collections::{HashSet, HashMap};
-use std::fs::{create_dir_all, write, read_dir, read_to_string, remove_file};
-use std::path::PathBuf;
+use std::fs::{create_dir_all, write, read_dir, read_to_string, remove_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+/// Delay before retrying to upload the outbox after the first failed sync.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Upper bound for the delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Minimum time between two revisions of a note, edits in between are not kept.
+const REVISION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Revisions older than this are removed.
+const MAX_REVISION_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Maximum number of revisions kept per note.
+const MAX_REVISIONS: usize = 50;
+
+/// Past version of a note.
+pub struct Revision {
+    /// Time at which the version was replaced.
+    pub created_at: DateTime<Utc>,
+    pub note: Note,
+}
+
 pub struct Storage {
     path: PathBuf,
     pub items: HashMap<Uuid, Item>,
@@ -20,8 +45,199 @@ pub struct Storage {
     /// Contains uuids of notes that have not been flushed yet
     dirty: HashSet<Uuid>,
 
-    /// The storage automatically syncs with the client if it exists.
-    pub client: Option<remote::Client>,
+    /// Encrypted items that refer to an items key we have not received yet.
+    pending: HashMap<Uuid, Envelope>,
+
+    /// Encrypted items, deletions included, that have not been uploaded yet. It is persisted so
+    /// that changes made offline survive restarts.
+    outbox: HashMap<Uuid, Envelope>,
+
+    /// Earliest time to retry uploading the outbox after a failed sync.
+    retry_at: Option<Instant>,
+
+    /// Delay before the next retry, doubled after each failed sync.
+    backoff: Duration,
+
+    /// Token of the last successful sync, `None` if we have never synced.
+    sync_token: Option<String>,
+
+    /// Set if the storage is synced with a remote.
+    remote: bool,
+
+    /// Uuids of outbox items that are currently being uploaded.
+    in_flight: Option<HashSet<Uuid>>,
+}
+
+/// Outcome of recovering an interrupted password change.
+pub enum Recovery {
+    /// The change has been completed with these credentials, which must be stored before calling
+    /// `Storage::finish_password_change`.
+    Completed(Credentials),
+    /// The change has been rolled back, the old credentials are still valid.
+    RolledBack,
+    /// It is unknown whether the remote accepted the new password, the change is kept until we
+    /// can find out.
+    Deferred,
+}
+
+/// Encryption parameters of a staged password change. The new password itself is kept in the
+/// keyring.
+#[derive(Serialize, Deserialize)]
+struct StagedChange {
+    pub cost: u32,
+    pub nonce: String,
+    pub version: String,
+
+    /// Set as soon as the remote accepted the new password, from then on the change must be
+    /// completed rather than rolled back.
+    pub committed: bool,
+}
+
+/// Directory containing the revisions of all notes.
+fn revisions_path(path: &Path) -> PathBuf {
+    path.join("revisions")
+}
+
+/// Revision files of a note, named by the millisecond timestamp they were taken at, oldest first.
+fn revision_files(path: &Path) -> Result<Vec<(i64, PathBuf)>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+
+    for entry in read_dir(path)? {
+        let path = entry?.path();
+
+        if let Some(timestamp) = path.file_name().and_then(|name| name.to_str()?.parse::<i64>().ok()) {
+            files.push((timestamp, path));
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Directory containing items re-encrypted for a password change.
+fn staging_path(path: &Path) -> PathBuf {
+    path.with_extension("password-change")
+}
+
+/// File containing the encryption parameters of a staged password change. It is written after
+/// all items are staged and removed once the new credentials are stored everywhere.
+fn staged_change_path(path: &Path) -> PathBuf {
+    path.with_extension("password-change.toml")
+}
+
+fn read_staged_change(path: &Path) -> Result<StagedChange> {
+    Ok(toml::from_str(&read_to_string(staged_change_path(path))?)?)
+}
+
+fn write_staged_change(path: &Path, staged: &StagedChange) -> Result<()> {
+    write(staged_change_path(path), toml::to_string(staged)?)?;
+    Ok(())
+}
+
+/// Replace the items with the staged ones. This is idempotent so it can be repeated when it was
+/// interrupted.
+fn swap_staged_items(path: &Path) -> Result<()> {
+    let staging = staging_path(path);
+    let old = path.with_extension("old");
+
+    if staging.exists() {
+        if path.exists() {
+            rename(path, &old)?;
+        }
+
+        rename(&staging, path)?;
+    }
+
+    if old.exists() {
+        remove_dir_all(&old)?;
+    }
+
+    Ok(())
+}
+
+/// Remove staged items and parameters.
+fn discard_staged_change(path: &Path) -> Result<()> {
+    let staging = staging_path(path);
+    let staged = staged_change_path(path);
+
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+
+    if staged.exists() {
+        remove_file(&staged)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the parameters of a completed password change of the items in `path`.
+fn finish_password_change_at(path: &Path) -> Result<()> {
+    remove_file(staged_change_path(path))?;
+    Ok(())
+}
+
+/// Complete or roll back an interrupted password change of the items in `path`, see
+/// `Storage::recover_password_change`.
+fn recover_password_change_at(path: &Path, credentials: &Credentials, pending: Option<String>, server: Option<&str>) -> Result<Recovery> {
+    if !staged_change_path(path).exists() {
+        // Staging has been interrupted before it was complete.
+        discard_staged_change(path)?;
+        return Ok(Recovery::RolledBack);
+    }
+
+    let staged = read_staged_change(path)?;
+
+    let password = match pending {
+        Some(password) => password,
+        None if staged.committed => {
+            return Err(anyhow!("Password change was committed but the new password is missing"));
+        }
+        None => {
+            discard_staged_change(path)?;
+            return Ok(Recovery::RolledBack);
+        }
+    };
+
+    let new_credentials = Credentials {
+        identifier: credentials.identifier.clone(),
+        cost: staged.cost,
+        nonce: staged.nonce,
+        password,
+        version: staged.version,
+    };
+
+    // We might have been interrupted after the remote accepted the password but before we
+    // could take note of it. Only a rejected password proves that it did not.
+    let committed = match server {
+        _ if staged.committed => true,
+        Some(server) => match remote::Client::new_sign_in(server, &new_credentials) {
+            Ok(_) => true,
+            Err(err) => match err.downcast_ref::<RemoteError>() {
+                Some(RemoteError::InvalidCredentials(_)) => false,
+                _ => {
+                    g_warning!(APP_DOMAIN, "Could not check if the password has been changed: {}", err);
+                    return Ok(Recovery::Deferred);
+                }
+            },
+        },
+        None => false,
+    };
+
+    if committed {
+        g_info!(APP_DOMAIN, "Completing interrupted password change");
+        swap_staged_items(path)?;
+        Ok(Recovery::Completed(new_credentials))
+    }
+    else {
+        g_info!(APP_DOMAIN, "Rolling back interrupted password change");
+        discard_staged_change(path)?;
+        Ok(Recovery::RolledBack)
+    }
 }
 
 fn data_path_from_identifier(identifier: &str) -> Result<PathBuf> {
@@ -38,16 +254,57 @@ fn data_path_from_identifier(identifier: &str) -> Result<PathBuf> {
     }
 }
 
+/// Copy of a note whose changes conflicted with the server's version.
+fn conflicted_copy(note: &Note) -> Note {
+    Note {
+        title: format!("{} (conflicted copy)", note.title),
+        text: note.text.clone(),
+        created_at: note.created_at,
+        updated_at: Utc::now(),
+        uuid: Uuid::new_v4(),
+        flags: note.flags,
+        extra: note.extra.clone(),
+    }
+}
+
+fn notes_differ(ours: &Note, theirs: &Note) -> bool {
+    ours.title != theirs.title || ours.text != theirs.text || ours.flags != theirs.flags
+}
+
 impl Storage {
-    pub fn new(credentials: &Credentials, client: Option<remote::Client>) -> Result<Self> {
+    pub fn new(credentials: &Credentials) -> Result<Self> {
+        let mut storage = Storage::load(credentials)?;
+        storage.ensure_items_key()?;
+        Ok(storage)
+    }
+
+    /// Read local items of a storage that is about to be synced. Unlike `new`, no items key is
+    /// created because the remote's is retrieved with the first sync.
+    pub fn open(credentials: &Credentials) -> Result<Self> {
+        Storage::load(credentials)
+    }
+
+    /// Read local items.
+    fn load(credentials: &Credentials) -> Result<Self> {
+        Storage::load_from(data_path_from_identifier(&credentials.identifier)?, credentials)
+    }
+
+    /// Read local items from `path`.
+    fn load_from(path: PathBuf, credentials: &Credentials) -> Result<Self> {
         let mut storage = Self {
-            path: data_path_from_identifier(&credentials.identifier)?,
+            path,
             items: HashMap::new(),
             credentials: credentials.clone(),
-            crypto: Crypto::new(&credentials)?,
+            crypto: Crypto::new(credentials)?,
             current: None,
             dirty: HashSet::new(),
-            client: client,
+            pending: HashMap::new(),
+            outbox: HashMap::new(),
+            retry_at: None,
+            backoff: MIN_BACKOFF,
+            sync_token: None,
+            remote: false,
+            in_flight: None,
         };
 
         let mut items: Vec<Envelope> = Vec::new();
@@ -59,7 +316,12 @@ impl Storage {
                 let file_path = entry?.path();
 
                 if let Some(file_name) = file_path.file_name() {
-                    let uuid = Uuid::parse_str(file_name.to_string_lossy().as_ref())?;
+                    // Skip bookkeeping files such as the sync token.
+                    let uuid = match Uuid::parse_str(file_name.to_string_lossy().as_ref()) {
+                        Ok(uuid) => uuid,
+                        Err(_) => continue,
+                    };
+
                     let contents = read_to_string(file_path)?;
                     let item = Envelope::from_str(&contents)?;
 
@@ -67,41 +329,181 @@ impl Storage {
                         return Err(anyhow!("File is corrupted"));
                     }
 
-                    storage.items.insert(uuid, item.decrypt(&storage.crypto)?);
                     items.push(item);
                 }
             }
         }
 
-        if let Some(client) = &mut storage.client {
-            g_info!(APP_DOMAIN, "Syncing with remote");
+        storage.decrypt_items(&items)?;
+        storage.sync_token = storage.read_sync_token()?;
+        storage.outbox = storage.read_outbox()?;
+        storage.remote = storage.sync_token.is_some();
+
+        Ok(storage)
+    }
 
-            // Use all items we haven't synced yet. For now pretend we have never synced an item.
-            // Decrypt, flush and show notes we have retrieved from the initial sync.
-            let items = client.sync(items)?;
-            storage.insert_encrypted_items(&items)?;
+    /// Create storage from vector of encrypted items. Items of read-only schemes are migrated to
+    /// the current scheme, so the credentials of the storage may differ from the passed ones.
+    pub fn new_from_items(credentials: &Credentials, items: &[Envelope]) -> Result<Self> {
+        if crypto::is_read_only(&credentials.version) {
+            return Storage::migrate_from_items(credentials, items);
         }
 
+        let mut storage = Storage::load(credentials)?;
+        storage.insert_encrypted_items(items)?;
+        storage.ensure_items_key()?;
         Ok(storage)
     }
 
-    /// Create storage from vector of encrypted items.
-    pub fn new_from_items(credentials: &Credentials, items: &Vec<Envelope>) -> Result<Self> {
-        let mut storage = Storage::new(credentials, None)?;
-        storage.insert_encrypted_items(items)?;
+    /// Decrypt items of a read-only scheme and re-encrypt them with fresh credentials of the
+    /// current scheme.
+    fn migrate_from_items(credentials: &Credentials, items: &[Envelope]) -> Result<Self> {
+        let legacy = Crypto::new(credentials)?;
+        let upgraded = Credentials::from_defaults(&credentials.identifier, &credentials.password, crypto::DEFAULT_VERSION);
+
+        g_info!(APP_DOMAIN, "Migrating {} items from {} to {}", items.len(), credentials.version, upgraded.version);
+
+        let mut storage = Storage::load(&upgraded)?;
+        storage.ensure_items_key()?;
+
+        for item in items {
+            match item.decrypt(&legacy) {
+                Ok(decrypted) => {
+                    storage.items.insert(item.uuid, decrypted);
+                    storage.dirty.insert(item.uuid);
+                }
+                Err(err) => {
+                    match err {
+                        CryptoError::Other(e) => return Err(e),
+                        e => return Err(anyhow!("{}", e)),
+                    }
+                }
+            }
+        }
+
+        storage.flush_dirty()?;
         Ok(storage)
     }
 
+    /// Re-encrypt all items with new credentials derived from `password` and a fresh nonce and
+    /// write them to a staging directory. Nothing changes until `change_password` is called.
+    pub fn stage_password_change(&self, password: &str) -> Result<Credentials> {
+        let credentials = Credentials::from_defaults(&self.credentials.identifier, password, crypto::DEFAULT_VERSION);
+        let mut crypto = Crypto::new(&credentials)?;
+        let mut new_items_key = None;
+
+        for item in self.items.values() {
+            if let Item::ItemsKey(items_key) = item {
+                crypto.add_items_key(items_key)?;
+            }
+        }
+
+        // Upgrading from a scheme without items keys.
+        if crypto.uses_items_keys() && crypto.default_items_key().is_none() {
+            let items_key = ItemsKey::new(&credentials.version, true);
+            crypto.add_items_key(&items_key)?;
+            new_items_key = Some(Item::ItemsKey(items_key));
+        }
+
+        discard_staged_change(&self.path)?;
+
+        let staging = staging_path(&self.path);
+        create_dir_all(&staging)?;
+
+        for item in self.items.values().chain(new_items_key.iter()) {
+            let envelope = item.encrypt(&crypto)?;
+            write(staging.join(item.uuid().to_hyphenated().to_string()), envelope.to_string()?)?;
+        }
+
+        for envelope in self.pending.values() {
+            write(staging.join(envelope.uuid.to_hyphenated().to_string()), envelope.to_string()?)?;
+        }
+
+        for uuid in self.items.keys() {
+            let staged_revisions = revisions_path(&staging).join(uuid.to_hyphenated().to_string());
+
+            for revision in self.revisions(uuid)? {
+                let envelope = Item::Note(revision.note).encrypt(&crypto)?;
+                create_dir_all(&staged_revisions)?;
+                write(staged_revisions.join(revision.created_at.timestamp_millis().to_string()), envelope.to_string()?)?;
+            }
+        }
+
+        write_staged_change(&self.path, &StagedChange {
+            cost: credentials.cost,
+            nonce: credentials.nonce.clone(),
+            version: credentials.version.clone(),
+            committed: false,
+        })?;
+
+        Ok(credentials)
+    }
+
+    /// Apply a staged password change once the remote, if any, accepted the new password:
+    /// replace the local items with the staged ones and queue them for upload.
+    pub fn commit_password_change(&mut self, credentials: &Credentials) -> Result<()> {
+        let mut staged = read_staged_change(&self.path)?;
+
+        staged.committed = true;
+        write_staged_change(&self.path, &staged)?;
+        swap_staged_items(&self.path)?;
+
+        let mut storage = Storage::load_from(self.path.clone(), credentials)?;
+        storage.current = self.current;
+
+        // The staged items come without a sync token, so connecting queues all of them. If
+        // uploading fails, that happens again on the next start.
+        if self.remote {
+            storage.connect()?;
+        }
+
+        *self = storage;
+        Ok(())
+    }
+
+    /// Roll back a password change that has not been committed. Once the remote accepted the
+    /// new password, the change can only be completed.
+    pub fn abort_password_change(&self) -> Result<()> {
+        if staged_change_path(&self.path).exists() && read_staged_change(&self.path)?.committed {
+            return Err(anyhow!("Password change has already been committed"));
+        }
+
+        discard_staged_change(&self.path)
+    }
+
+    /// Forget about a password change once the new credentials are stored in the keyring and the
+    /// configuration.
+    pub fn finish_password_change(identifier: &str) -> Result<()> {
+        finish_password_change_at(&data_path_from_identifier(identifier)?)
+    }
+
+    /// Complete or roll back a password change that was interrupted. `pending` is the new
+    /// password stored in the keyring.
+    pub fn recover_password_change(credentials: &Credentials, pending: Option<String>, server: Option<&str>) -> Result<Recovery> {
+        let path = data_path_from_identifier(&credentials.identifier)?;
+        recover_password_change_at(&path, credentials, pending, server)
+    }
+
+    /// Credentials the storage encrypts with.
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
     pub fn export(&self) -> Result<Exported> {
+        let mut items = self.items.values().map(|item| item.encrypt(&self.crypto)).collect::<Result<Vec<_>, _>>()?;
+
+        // Items we cannot decrypt yet are exported as they are.
+        items.extend(self.pending.values().cloned());
+
         Ok(Exported {
             auth_params: AuthParams::from_credentials(&self.credentials),
-            items: self.items.values().map(|item| item.encrypt(&self.crypto)).collect::<Result<Vec<_>, _>>()?,
+            items,
         })
     }
 
     /// Set the currently note to update.
     pub fn set_current_uuid(&mut self, uuid: &Uuid) -> Result<()> {
-        if !self.items.contains_key(&uuid) {
+        if !self.items.contains_key(uuid) {
             return Err(anyhow!(format!("{} does not exist", uuid)));
         }
 
@@ -109,49 +511,168 @@ impl Storage {
         Ok(())
     }
 
-    fn insert_encrypted_items(&mut self, items: &Vec<Envelope>) -> Result<()> {
-        for item in items {
-            let result = item.decrypt(&self.crypto);
+    /// Make sure there is a default items key if the encryption scheme requires one. This is the
+    /// case for new accounts and accounts that were never synced.
+    fn ensure_items_key(&mut self) -> Result<()> {
+        if !self.crypto.uses_items_keys() || self.crypto.default_items_key().is_some() {
+            return Ok(());
+        }
 
-            match result {
-                Ok(decrypted) => {
-                    self.items.insert(item.uuid, decrypted);
-                    self.flush(&item)?;
+        // An items key we could not decrypt is still ours, a second one would split the account.
+        if self.pending.values().any(|item| item.content_type == "SN|ItemsKey") {
+            return Err(anyhow!("Could not decrypt the items key"));
+        }
+
+        g_info!(APP_DOMAIN, "Creating default items key");
+
+        let items_key = ItemsKey::new(&self.credentials.version, true);
+        let uuid = items_key.uuid;
+
+        self.crypto.add_items_key(&items_key)?;
+        self.items.insert(uuid, Item::ItemsKey(items_key));
+        self.dirty.insert(uuid);
+        self.flush_dirty()
+    }
+
+    /// Decrypt a single item and insert it. Returns false if the item cannot be decrypted, it is
+    /// kept pending then.
+    fn decrypt_item(&mut self, item: &Envelope) -> Result<bool> {
+        if item.deleted == Some(true) {
+            return Ok(false);
+        }
+
+        match item.decrypt(&self.crypto) {
+            Ok(decrypted) => {
+                if let Item::ItemsKey(items_key) = &decrypted {
+                    self.crypto.add_items_key(items_key)?;
                 }
-                Err(err) => {
-                    match err {
-                        CryptoError::Other(e) => return Err(e),
-                        CryptoError::UnknownContentType(_) => { /* ignore this one */ }
-                        e => return Err(anyhow!("{}", e)),
+
+                self.pending.remove(&item.uuid);
+                self.items.insert(item.uuid, decrypted);
+                Ok(true)
+            }
+            Err(err) => {
+                // A single broken item must not keep us from syncing the others. It is kept as
+                // it is, so it is neither lost nor overwritten.
+                match err {
+                    CryptoError::UnknownItemsKey(items_key_id) => {
+                        g_info!(APP_DOMAIN, "Deferring {} until items key {} is known", item.uuid, items_key_id);
                     }
+                    err => {
+                        g_warning!(APP_DOMAIN, "Could not decrypt {}: {}", item.uuid, err);
+                    }
+                }
+
+                self.pending.insert(item.uuid, item.clone());
+                Ok(false)
+            }
+        }
+    }
+
+    /// Decrypt items keys first and then all other items. Items whose items key is not known are
+    /// kept pending and retried as soon as new items keys arrive, e.g. with a later sync.
+    fn decrypt_items(&mut self, items: &[Envelope]) -> Result<Vec<Uuid>> {
+        let (keys, others): (Vec<&Envelope>, Vec<&Envelope>) = items
+            .iter()
+            .partition(|item| item.content_type == "SN|ItemsKey");
+
+        let mut decrypted = Vec::new();
+
+        for item in keys.iter().chain(others.iter()) {
+            if self.decrypt_item(item)? {
+                decrypted.push(item.uuid);
+            }
+        }
+
+        if !keys.is_empty() && !self.pending.is_empty() {
+            let pending = self.pending.values().cloned().collect::<Vec<_>>();
+
+            for item in pending {
+                if self.decrypt_item(&item)? {
+                    decrypted.push(item.uuid);
                 }
             }
         }
 
-        Ok(())
+        Ok(decrypted)
+    }
+
+    /// Insert encrypted items and remove those deleted elsewhere. Returns the uuids of inserted
+    /// and removed items.
+    fn insert_encrypted_items(&mut self, items: &[Envelope]) -> Result<Vec<Uuid>> {
+        // Tombstones have no content to decrypt.
+        let (tombstones, items): (Vec<Envelope>, Vec<Envelope>) = items
+            .iter()
+            .cloned()
+            .partition(|item| item.deleted == Some(true));
+
+        let mut uuids = Vec::new();
+
+        for tombstone in &tombstones {
+            if self.remove(&tombstone.uuid)? {
+                g_info!(APP_DOMAIN, "Removed {} deleted on remote", tombstone.uuid);
+                uuids.push(tombstone.uuid);
+            }
+        }
+
+        uuids.extend(self.decrypt_items(&items)?);
+
+        // Write pending items as well, so they can be decrypted on a later start.
+        for item in &items {
+            if self.items.contains_key(&item.uuid) || self.pending.contains_key(&item.uuid) {
+                self.flush_to_disk(&item.uuid, item)?;
+            }
+        }
+
+        Ok(uuids)
+    }
+
+    /// Remove an item locally. Returns false if it did not exist.
+    fn remove(&mut self, uuid: &Uuid) -> Result<bool> {
+        let path = self.path_from_uuid(uuid);
+        let existed = path.exists();
+
+        if existed {
+            g_info!(APP_DOMAIN, "Deleting {:?}", path);
+            remove_file(path)?;
+        }
+
+        let revisions = self.note_revisions_path(uuid);
+
+        if revisions.exists() {
+            remove_dir_all(revisions)?;
+        }
+
+        if self.current == Some(*uuid) {
+            self.current = None;
+        }
+
+        self.dirty.remove(uuid);
+        self.pending.remove(uuid);
+        Ok(self.items.remove(uuid).is_some() || existed)
     }
 
     fn get_uuid(&self) -> Result<Uuid> {
-        Ok(self.current.ok_or(anyhow!("No current uuid set"))?)
+        self.current.ok_or_else(|| anyhow!("No current uuid set"))
     }
 
     fn get_note(&self) -> Result<&Note> {
         let uuid = self.get_uuid()?;
-        let item = self.items.get(&uuid).ok_or(anyhow!("uuid mapping not found"))?;
+        let item = self.items.get(&uuid).ok_or_else(|| anyhow!("uuid mapping not found"))?;
 
         match item {
             Item::Note(note) => Ok(note),
-            Item::Tag(_) => panic!("Current uuid is a tag"),
+            _ => panic!("Current uuid is not a note"),
         }
     }
 
     fn get_note_mut(&mut self) -> Result<&mut Note> {
         let uuid = self.get_uuid()?;
-        let item = self.items.get_mut(&uuid).ok_or(anyhow!("uuid mapping not found"))?;
+        let item = self.items.get_mut(&uuid).ok_or_else(|| anyhow!("uuid mapping not found"))?;
 
         match item {
             Item::Note(note) => Ok(note),
-            Item::Tag(_) => panic!("Current uuid is a tag"),
+            _ => panic!("Current uuid is not a note"),
         }
     }
 
@@ -185,111 +706,1202 @@ impl Storage {
         Ok(self.get_note()?.title.clone())
     }
 
+    /// Update the flags of the currently selected item.
+    pub fn set_flags(&mut self, flags: NoteFlags) -> Result<()> {
+        let note = self.get_note_mut()?;
+        note.updated_at = Utc::now();
+        note.flags = flags;
+
+        self.dirty.insert(self.get_uuid()?);
+        Ok(())
+    }
+
+    /// Get flags of the currently selected item.
+    pub fn get_flags(&self) -> Result<NoteFlags> {
+        Ok(self.get_note()?.flags)
+    }
+
     fn flush_to_disk(&self, uuid: &Uuid, item: &Envelope) -> Result<()> {
-        let path = self.path_from_uuid(&uuid);
+        let path = self.path_from_uuid(uuid);
 
         if let Some(parent) = path.parent() {
             if !parent.exists() {
-                create_dir_all(&parent)?;
+                create_dir_all(parent)?;
             }
         }
 
+        if item.content_type == "Note" {
+            self.save_revision(uuid, false)?;
+        }
+
         write(&path, item.to_string()?)?;
 
         Ok(())
     }
 
-    /// Write encrypted item to disk and sync with remote.
-    fn flush(&mut self, item: &Envelope) -> Result<()> {
-        self.flush_to_disk(&item.uuid, &item)?;
-
-        if let Some(client) = &mut self.client {
-            g_info!(APP_DOMAIN, "Syncing {}", item.uuid);
+    fn note_revisions_path(&self, uuid: &Uuid) -> PathBuf {
+        revisions_path(&self.path).join(uuid.to_hyphenated().to_string())
+    }
 
-            let copy = Envelope {
-                uuid: item.uuid,
-                content: item.content.clone(),
-                content_type: item.content_type.clone(),
-                enc_item_key: item.enc_item_key.clone(),
-                created_at: item.created_at,
-                updated_at: item.updated_at,
-                deleted: item.deleted,
-            };
+    /// Keep the version of a note on disk as a revision before it is overwritten. Unless `force`
+    /// is set, nothing is kept if the latest revision is more recent than `REVISION_INTERVAL`.
+    fn save_revision(&self, uuid: &Uuid, force: bool) -> Result<()> {
+        let current = self.path_from_uuid(uuid);
 
-            client.sync(vec![copy])?;
+        if !current.exists() {
+            return Ok(());
         }
 
-        Ok(())
-    }
-
-    /// Encrypt all dirty items, write them to disk and sync with remote.
-    pub fn flush_dirty(&mut self) -> Result<()> {
-        let mut items: Vec<Envelope> = Vec::new();
+        let path = self.note_revisions_path(uuid);
+        let now = Utc::now();
+        let interval = ChronoDuration::from_std(REVISION_INTERVAL)?;
 
-        for uuid in &self.dirty {
-            let item = self.items.get(uuid).ok_or(anyhow!("uuid dirty but not found"))?;
-            let envelope = item.encrypt(&self.crypto)?;
+        let recent = revision_files(&path)?
+            .last()
+            .map_or(false, |(timestamp, _)| Utc.timestamp_millis(*timestamp) + interval > now);
 
-            self.flush_to_disk(&uuid, &envelope)?;
-            items.push(envelope);
+        if recent && !force {
+            return Ok(());
         }
 
-        if let Some(client) = &mut self.client {
-            g_info!(APP_DOMAIN, "Syncing dirty items");
-            client.sync(items)?;
-        }
+        create_dir_all(&path)?;
+        write(path.join(now.timestamp_millis().to_string()), read_to_string(current)?)?;
+        self.prune_revisions(uuid)
+    }
 
-        self.dirty.clear();
+    /// Remove revisions of a note that are too old or too many.
+    fn prune_revisions(&self, uuid: &Uuid) -> Result<()> {
+        let files = revision_files(&self.note_revisions_path(uuid))?;
+        let oldest = Utc::now() - ChronoDuration::from_std(MAX_REVISION_AGE)?;
+        let excess = files.len().saturating_sub(MAX_REVISIONS);
+
+        for (index, (timestamp, path)) in files.iter().enumerate() {
+            if index < excess || Utc.timestamp_millis(*timestamp) < oldest {
+                remove_file(path)?;
+            }
+        }
 
         Ok(())
     }
 
-    /// Delete note from storage.
-    pub fn delete(&mut self, uuid: &Uuid) -> Result<()> {
-        if self.dirty.contains(uuid) {
-            self.dirty.remove(&uuid);
+    /// Past versions of a note, newest first.
+    pub fn revisions(&self, uuid: &Uuid) -> Result<Vec<Revision>> {
+        let mut revisions = Vec::new();
+
+        for (timestamp, path) in revision_files(&self.note_revisions_path(uuid))?.into_iter().rev() {
+            let envelope = Envelope::from_str(&read_to_string(&path)?)?;
+
+            match envelope.decrypt(&self.crypto) {
+                Ok(Item::Note(note)) => revisions.push(Revision {
+                    created_at: Utc.timestamp_millis(timestamp),
+                    note,
+                }),
+                Ok(_) => {}
+                Err(err) => g_warning!(APP_DOMAIN, "Could not decrypt revision {:?}: {}", path, err),
+            }
         }
 
-        if let Some(client) = &mut self.client {
-            if let Some(item) = self.items.get(&uuid) {
-                let mut envelope = item.encrypt(&self.crypto)?;
-                envelope.deleted = Some(true);
+        Ok(revisions)
+    }
+
+    /// Replace title and text of a note with those of a revision. The version replaced is kept
+    /// as a revision itself, so restoring can be undone.
+    pub fn restore_revision(&mut self, revision: &Revision) -> Result<()> {
+        let uuid = revision.note.uuid;
 
-                // Apparently, we do not receive the item back as marked deleted
-                // but on subsequent syncs only.
-                client.sync(vec![envelope])?;
+        // Write pending edits first, they would not be part of the kept version otherwise.
+        self.flush_dirty()?;
+        self.save_revision(&uuid, true)?;
+
+        match self.items.get_mut(&uuid) {
+            Some(Item::Note(note)) => {
+                note.title = revision.note.title.clone();
+                note.text = revision.note.text.clone();
+                note.updated_at = Utc::now();
             }
+            _ => return Err(anyhow!("Note {} not found", uuid)),
         }
 
-        let path = self.path_from_uuid(&uuid);
-        g_info!(APP_DOMAIN, "Deleting {:?}", path);
-        remove_file(path)?;
-        self.items.remove(&uuid);
+        self.dirty.insert(uuid);
+        self.flush_dirty()
+    }
+
+    /// Mark the storage as synced with a remote. If it has never been synced, all local items
+    /// are queued for upload.
+    pub fn connect(&mut self) -> Result<()> {
+        if self.sync_token.is_none() {
+            let mut items = self.items
+                .values()
+                .map(|item| item.encrypt(&self.crypto))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            items.extend(self.pending.values().cloned());
 
+            self.remote = true;
+            self.queue(items)?;
+        }
+
+        self.remote = true;
+        self.reset_backoff();
         Ok(())
     }
 
-    fn path_from_uuid(&self, uuid: &Uuid) -> PathBuf {
-        let mut path = PathBuf::from(&self.path);
-        path.push(uuid.to_hyphenated().to_string());
-        path
+    /// Items to upload and the token to sync from. Returns nothing if another sync is still
+    /// running, the retry delay has not passed yet or, unless we also want to `pull` remote
+    /// changes, there is nothing to upload.
+    pub fn sync_request(&mut self, pull: bool) -> Option<(Vec<Envelope>, Option<String>)> {
+        if !self.remote || self.in_flight.is_some() {
+            return None;
+        }
+
+        if self.retry_at.map_or(false, |at| at > Instant::now()) || (!pull && self.outbox.is_empty()) {
+            return None;
+        }
+
+        self.in_flight = Some(self.outbox.keys().cloned().collect());
+        Some((self.outbox.values().cloned().collect(), self.sync_token.clone()))
     }
 
-    /// Create a new note and return its new uuid.
-    pub fn create_note(&mut self) -> Uuid {
-        let now = Utc::now();
-        let uuid = Uuid::new_v4();
+    /// Apply the result of a sync requested with `sync_request`: remove uploaded items from the
+    /// outbox, insert retrieved items and resolve conflicts. Returns the uuids of items that have
+    /// been retrieved or added while resolving conflicts.
+    pub fn apply_sync(&mut self, result: SyncResult) -> Result<Vec<Uuid>> {
+        // Items that changed again while being uploaded stay in the outbox.
+        if let Some(uploaded) = self.in_flight.take() {
+            self.outbox.retain(|uuid, _| !uploaded.contains(uuid));
+        }
 
-        let note = Note {
-            title: "".to_owned(),
-            text: "".to_owned(),
-            created_at: now,
-            updated_at: now,
-            uuid: uuid,
-        };
+        self.retry_at = None;
+        self.backoff = MIN_BACKOFF;
+        self.write_outbox()?;
 
-        self.items.insert(uuid, Item::Note(note));
+        g_info!(APP_DOMAIN, "Retrieved {} items", result.retrieved.len());
 
-        uuid
+        // Local changes that have not been uploaded yet win over retrieved versions, which are
+        // kept as conflicted copies if they differ.
+        let (retrieved, superseded): (Vec<Envelope>, Vec<Envelope>) = result.retrieved
+            .iter()
+            .cloned()
+            .partition(|item| !self.dirty.contains(&item.uuid) && !self.outbox.contains_key(&item.uuid));
+
+        let mut uuids = self.insert_encrypted_items(&retrieved)?;
+        uuids.extend(superseded.iter().filter_map(|item| self.keep_remote_copy(item)));
+        uuids.extend(self.resolve_conflicts(&result)?);
+
+        // New accounts get their items key once we know the remote does not have one.
+        self.ensure_items_key()?;
+
+        // Only now that everything is applied we may continue from the new token, otherwise
+        // retrieved items would be skipped after a failure.
+        if result.sync_token.is_some() {
+            self.sync_token = result.sync_token.clone();
+            self.write_sync_token()?;
+        }
+
+        Ok(uuids)
+    }
+
+    /// Keep a retrieved version of a note with local changes as conflicted copy if it differs.
+    fn keep_remote_copy(&mut self, item: &Envelope) -> Option<Uuid> {
+        let copy = match (self.items.get(&item.uuid), item.decrypt(&self.crypto)) {
+            (Some(Item::Note(ours)), Ok(Item::Note(theirs))) if notes_differ(ours, &theirs) => conflicted_copy(&theirs),
+            _ => return None,
+        };
+
+        g_info!(APP_DOMAIN, "Keeping remote version of {} as {}", item.uuid, copy.uuid);
+
+        let uuid = copy.uuid;
+        self.dirty.insert(uuid);
+        self.items.insert(uuid, Item::Note(copy));
+        Some(uuid)
+    }
+
+    /// Keep the outbox after a failed sync and retry after an increasing delay, but not before
+    /// `retry_after` if the remote asked us to wait.
+    pub fn sync_failed(&mut self, retry_after: Option<Duration>) {
+        self.in_flight = None;
+        self.schedule_retry(retry_after.unwrap_or_default());
+    }
+
+    /// Time until the outbox should be uploaded again, if there is anything to upload.
+    pub fn retry_in(&self) -> Option<Duration> {
+        if self.outbox.is_empty() || !self.remote || self.in_flight.is_some() {
+            return None;
+        }
+
+        Some(self.retry_at.map_or(Duration::from_secs(0), |at| at.saturating_duration_since(Instant::now())))
+    }
+
+    /// Retry uploading the outbox right away, e.g. because the network became available again.
+    pub fn reset_backoff(&mut self) {
+        self.retry_at = None;
+        self.backoff = MIN_BACKOFF;
+    }
+
+    fn schedule_retry(&mut self, at_least: Duration) {
+        let delay = cmp::max(self.backoff, at_least);
+        g_warning!(APP_DOMAIN, "Could not sync, retrying in {} seconds", delay.as_secs());
+        self.retry_at = Some(Instant::now() + delay);
+        self.backoff = cmp::min(self.backoff * 2, MAX_BACKOFF);
+    }
+
+    /// Queue encrypted items for upload, replacing older versions of the same items. Only
+    /// storages that are or have been synced with a remote need an outbox.
+    fn queue(&mut self, items: Vec<Envelope>) -> Result<()> {
+        if !self.remote || items.is_empty() {
+            return Ok(());
+        }
+
+        for item in items {
+            if let Some(in_flight) = &mut self.in_flight {
+                in_flight.remove(&item.uuid);
+            }
+
+            self.outbox.insert(item.uuid, item);
+        }
+
+        self.write_outbox()
+    }
+
+    fn outbox_path(&self) -> PathBuf {
+        self.path.join("outbox")
+    }
+
+    fn read_outbox(&self) -> Result<HashMap<Uuid, Envelope>> {
+        let path = self.outbox_path();
+
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let items: Vec<Envelope> = serde_json::from_str(&read_to_string(path)?)?;
+        Ok(items.into_iter().map(|item| (item.uuid, item)).collect())
+    }
+
+    fn write_outbox(&self) -> Result<()> {
+        let path = self.outbox_path();
+
+        if self.outbox.is_empty() {
+            if path.exists() {
+                remove_file(path)?;
+            }
+
+            return Ok(());
+        }
+
+        create_dir_all(&self.path)?;
+        write(path, serde_json::to_string(&self.outbox.values().collect::<Vec<_>>())?)?;
+        Ok(())
+    }
+
+    fn sync_token_path(&self) -> PathBuf {
+        self.path.join("sync-token")
+    }
+
+    fn read_sync_token(&self) -> Result<Option<String>> {
+        let path = self.sync_token_path();
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let token = read_to_string(path)?.trim().to_owned();
+        Ok(if token.is_empty() { None } else { Some(token) })
+    }
+
+    /// Remember how far we have synced, so the next start only retrieves what changed since.
+    fn write_sync_token(&self) -> Result<()> {
+        if let Some(token) = &self.sync_token {
+            create_dir_all(&self.path)?;
+            write(self.sync_token_path(), token)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve conflicts of a sync. Diverging versions of a note are kept by taking the server's
+    /// version and saving ours as a conflicted copy. Returns the uuids of items that have been
+    /// added in the process.
+    fn resolve_conflicts(&mut self, result: &SyncResult) -> Result<Vec<Uuid>> {
+        let mut added = Vec::new();
+
+        for conflict in &result.conflicts {
+            let uuid = match conflict.uuid() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            match conflict.kind {
+                ConflictType::SyncConflict => {
+                    let server_item = match result.server_item(conflict) {
+                        Some(item) => item.clone(),
+                        None => {
+                            g_warning!(APP_DOMAIN, "Sync conflict for {} without server item", uuid);
+                            continue;
+                        }
+                    };
+
+                    // Items keys must never be replaced, other items may have been encrypted
+                    // with ours.
+                    if let Some(Item::ItemsKey(_)) = self.items.get(&uuid) {
+                        g_warning!(APP_DOMAIN, "Keeping local items key {} despite conflict", uuid);
+                        continue;
+                    }
+
+                    let copy = match (self.items.get(&uuid), server_item.decrypt(&self.crypto)) {
+                        (Some(Item::Note(ours)), Ok(Item::Note(theirs))) if notes_differ(ours, &theirs) => {
+                            Some(conflicted_copy(ours))
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(copy) = copy {
+                        g_info!(APP_DOMAIN, "Keeping conflicting version of {} as {}", uuid, copy.uuid);
+
+                        // The note being edited must not be overwritten, so editing continues
+                        // with our version.
+                        if self.current == Some(uuid) {
+                            self.current = Some(copy.uuid);
+                        }
+
+                        added.push(copy.uuid);
+                        self.dirty.insert(copy.uuid);
+                        self.items.insert(copy.uuid, Item::Note(copy));
+                    }
+
+                    self.dirty.remove(&uuid);
+                    self.insert_encrypted_items(&[server_item])?;
+                    added.push(uuid);
+                }
+                ConflictType::UuidConflict => {
+                    // Move our item to a fresh uuid, the old one belongs to someone else.
+                    if let Some(item) = self.items.remove(&uuid) {
+                        let item = item.with_uuid(Uuid::new_v4());
+                        let new_uuid = item.uuid();
+
+                        g_info!(APP_DOMAIN, "Moving {} to {} because of uuid conflict", uuid, new_uuid);
+
+                        let path = self.path_from_uuid(&uuid);
+
+                        if path.exists() {
+                            remove_file(path)?;
+                        }
+
+                        let revisions = self.note_revisions_path(&uuid);
+
+                        if revisions.exists() {
+                            rename(revisions, self.note_revisions_path(&new_uuid))?;
+                        }
+
+                        if self.current == Some(uuid) {
+                            self.current = Some(new_uuid);
+                        }
+
+                        self.dirty.remove(&uuid);
+                        self.dirty.insert(new_uuid);
+                        self.items.insert(new_uuid, item);
+                        added.push(new_uuid);
+                        added.push(uuid);
+                        added.extend(self.replace_references(&uuid, new_uuid));
+                    }
+                }
+                kind => {
+                    g_warning!(APP_DOMAIN, "Remote did not save {}: {:?}", uuid, kind);
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Make tags referring to `old` refer to `new` instead. Returns the uuids of the changed tags.
+    fn replace_references(&mut self, old: &Uuid, new: Uuid) -> Vec<Uuid> {
+        let mut changed = Vec::new();
+
+        for item in self.items.values_mut() {
+            if let Item::Tag(tag) = item {
+                if tag.replace_reference(old, new) {
+                    tag.updated_at = Utc::now();
+                    changed.push(tag.uuid);
+                }
+            }
+        }
+
+        self.dirty.extend(changed.iter().copied());
+        changed
+    }
+
+    /// Encrypt all dirty items, write them to disk and queue them for upload.
+    pub fn flush_dirty(&mut self) -> Result<()> {
+        let mut items: Vec<Envelope> = Vec::new();
+
+        for uuid in &self.dirty {
+            let item = self.items.get(uuid).ok_or_else(|| anyhow!("uuid dirty but not found"))?;
+            let envelope = item.encrypt(&self.crypto)?;
+
+            self.flush_to_disk(uuid, &envelope)?;
+            items.push(envelope);
+        }
+
+        // Items are safe in the outbox until they have been uploaded.
+        self.queue(items)?;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// All tags sorted by title.
+    pub fn tags(&self) -> Vec<&Tag> {
+        let mut tags = self.items
+            .values()
+            .filter_map(|item| match item {
+                Item::Tag(tag) => Some(tag),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        tags.sort_by(|a, b| a.title.cmp(&b.title));
+        tags
+    }
+
+    /// Tags of a note sorted by title.
+    pub fn tags_of(&self, note: &Uuid) -> Vec<&Tag> {
+        self.tags().into_iter().filter(|tag| tag.has_note(note)).collect()
+    }
+
+    /// Uuids of the notes tagged with `tag` that we have and that are neither archived nor
+    /// trashed.
+    pub fn notes_of(&self, tag: &Uuid) -> HashSet<Uuid> {
+        let shown = |uuid: &Uuid| match self.items.get(uuid) {
+            Some(Item::Note(note)) => !note.flags.archived && !note.flags.trashed,
+            _ => false,
+        };
+
+        match self.items.get(tag) {
+            Some(Item::Tag(tag)) => tag.notes().filter(shown).collect(),
+            _ => HashSet::new(),
+        }
+    }
+
+    fn get_tag_mut(&mut self, uuid: &Uuid) -> Result<&mut Tag> {
+        match self.items.get_mut(uuid) {
+            Some(Item::Tag(tag)) => Ok(tag),
+            _ => Err(anyhow!("Tag {} not found", uuid)),
+        }
+    }
+
+    /// Tag a note with the tag titled `title`, creating the tag if there is none yet. The change
+    /// is flushed right away. Returns the uuid of the tag.
+    pub fn tag_note(&mut self, note: &Uuid, title: &str) -> Result<Uuid> {
+        let existing = self.tags().into_iter().find(|tag| tag.title == title).map(|tag| tag.uuid);
+
+        let uuid = match existing {
+            Some(uuid) => uuid,
+            None => {
+                let tag = Tag::new(title);
+                let uuid = tag.uuid;
+                self.items.insert(uuid, Item::Tag(tag));
+                uuid
+            }
+        };
+
+        self.add_to_tag(note, &uuid)?;
+        Ok(uuid)
+    }
+
+    /// Tag a note with an existing tag. The change is flushed right away.
+    pub fn add_to_tag(&mut self, note: &Uuid, tag: &Uuid) -> Result<()> {
+        let item = self.get_tag_mut(tag)?;
+
+        if item.add_note(*note) {
+            item.updated_at = Utc::now();
+            self.dirty.insert(*tag);
+            self.flush_dirty()?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove a tag from a note. The change is flushed right away.
+    pub fn untag_note(&mut self, note: &Uuid, tag: &Uuid) -> Result<()> {
+        let item = self.get_tag_mut(tag)?;
+
+        if item.remove_note(note) {
+            item.updated_at = Utc::now();
+            self.dirty.insert(*tag);
+            self.flush_dirty()?;
+        }
+
+        Ok(())
+    }
+
+    /// Move a note to the trash or restore it from there. The change is flushed right away.
+    pub fn set_trashed(&mut self, uuid: &Uuid, trashed: bool) -> Result<()> {
+        match self.items.get_mut(uuid) {
+            Some(Item::Note(note)) => {
+                note.flags.trashed = trashed;
+                note.updated_at = Utc::now();
+            }
+            _ => return Err(anyhow!("Note {} not found", uuid)),
+        }
+
+        self.dirty.insert(*uuid);
+        self.flush_dirty()
+    }
+
+    /// Delete all trashed notes permanently and return their uuids.
+    pub fn empty_trash(&mut self) -> Result<Vec<Uuid>> {
+        let trashed = self.items
+            .values()
+            .filter_map(|item| match item {
+                Item::Note(note) if note.flags.trashed => Some(note.uuid),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for uuid in &trashed {
+            self.delete(uuid)?;
+        }
+
+        Ok(trashed)
+    }
+
+    /// Delete note permanently and queue the deletion for upload. Tags referring to the note
+    /// are updated as well.
+    pub fn delete(&mut self, uuid: &Uuid) -> Result<()> {
+        let tags = self.tags_of(uuid).iter().map(|tag| tag.uuid).collect::<Vec<_>>();
+
+        for tag in tags {
+            self.untag_note(uuid, &tag)?;
+        }
+
+        if let Some(item) = self.items.get(uuid) {
+            let mut envelope = item.encrypt(&self.crypto)?;
+            envelope.deleted = Some(true);
+
+            // Apparently, we do not receive the item back as marked deleted
+            // but on subsequent syncs only.
+            self.queue(vec![envelope])?;
+        }
+
+        self.remove(uuid)?;
+        Ok(())
+    }
+
+    fn path_from_uuid(&self, uuid: &Uuid) -> PathBuf {
+        let mut path = PathBuf::from(&self.path);
+        path.push(uuid.to_hyphenated().to_string());
+        path
+    }
+
+    /// Create a new note and return its new uuid.
+    pub fn create_note(&mut self) -> Uuid {
+        let now = Utc::now();
+        let uuid = Uuid::new_v4();
+
+        let note = Note {
+            title: "".to_owned(),
+            text: "".to_owned(),
+            created_at: now,
+            updated_at: now,
+            uuid,
+            flags: Default::default(),
+            extra: Default::default(),
+        };
+
+        self.items.insert(uuid, Item::Note(note));
+
+        uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use standardfile::remote::Client;
+    use standardfile_server::Server;
+    use std::fs::copy;
+    use std::thread::sleep;
+    use tempfile::TempDir;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            identifier: "foo@bar.com".to_string(),
+            cost: 3000,
+            nonce: "3f8ea1ffd8067c1550ca3ad78de71c9b6e68b5cb540e370c12065eca15d9a049".to_string(),
+            password: "secret".to_string(),
+            version: "003".to_string(),
+        }
+    }
+
+    /// Create a storage in a fresh temporary directory with a single note.
+    fn make_storage(dir: &TempDir) -> (Storage, Uuid) {
+        let mut storage = Storage::load_from(dir.path().join("items"), &credentials()).unwrap();
+        storage.ensure_items_key().unwrap();
+
+        let uuid = storage.create_note();
+        storage.set_current_uuid(&uuid).unwrap();
+        storage.set_text("foo").unwrap();
+        storage.flush_dirty().unwrap();
+
+        (storage, uuid)
+    }
+
+    fn text_of(storage: &Storage, uuid: &Uuid) -> String {
+        match &storage.items[uuid] {
+            Item::Note(note) => note.text.clone(),
+            _ => panic!("{} is not a note", uuid),
+        }
+    }
+
+    #[test]
+    fn test_change_password() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+
+        let new_credentials = storage.stage_password_change("new secret").unwrap();
+        storage.commit_password_change(&new_credentials).unwrap();
+        finish_password_change_at(&path).unwrap();
+
+        assert_eq!(storage.credentials().password, "new secret");
+        assert_eq!(text_of(&storage, &uuid), "foo");
+
+        // Nothing is left to recover on the next start.
+        let recovered = recover_password_change_at(&path, &credentials(), Some("new secret".to_string()), None).unwrap();
+        assert!(matches!(recovered, Recovery::RolledBack));
+
+        let storage = Storage::load_from(path.clone(), &new_credentials).unwrap();
+        assert_eq!(text_of(&storage, &uuid), "foo");
+
+        let storage = Storage::load_from(path, &credentials()).unwrap();
+        assert!(!storage.items.contains_key(&uuid));
+        assert!(storage.pending.contains_key(&uuid));
+    }
+
+    #[test]
+    fn test_recover_after_swap() {
+        let dir = TempDir::new().unwrap();
+        let (storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+
+        let new_credentials = storage.stage_password_change("new secret").unwrap();
+
+        // Crash right after the staged items replaced the old ones.
+        let mut staged = read_staged_change(&path).unwrap();
+        staged.committed = true;
+        write_staged_change(&path, &staged).unwrap();
+        swap_staged_items(&path).unwrap();
+
+        let recovered = match recover_password_change_at(&path, &credentials(), Some("new secret".to_string()), None).unwrap() {
+            Recovery::Completed(credentials) => credentials,
+            _ => panic!("Password change has not been completed"),
+        };
+        assert_eq!(recovered.nonce, new_credentials.nonce);
+        assert_eq!(recovered.version, new_credentials.version);
+        finish_password_change_at(&path).unwrap();
+
+        let storage = Storage::load_from(path, &recovered).unwrap();
+        assert_eq!(text_of(&storage, &uuid), "foo");
+    }
+
+    #[test]
+    fn test_committed_change_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let (storage, _) = make_storage(&dir);
+        let path = storage.path.clone();
+
+        storage.stage_password_change("new secret").unwrap();
+
+        let mut staged = read_staged_change(&path).unwrap();
+        staged.committed = true;
+        write_staged_change(&path, &staged).unwrap();
+
+        assert!(storage.abort_password_change().is_err());
+        assert!(recover_password_change_at(&path, &credentials(), None, None).is_err());
+        assert!(staging_path(&path).exists());
+        assert!(staged_change_path(&path).exists());
+    }
+
+    #[test]
+    fn test_recover_uncommitted() {
+        let dir = TempDir::new().unwrap();
+        let (storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+
+        storage.stage_password_change("new secret").unwrap();
+
+        let recovered = recover_password_change_at(&path, &credentials(), Some("new secret".to_string()), None).unwrap();
+        assert!(matches!(recovered, Recovery::RolledBack));
+        assert!(!staging_path(&path).exists());
+        assert!(!staged_change_path(&path).exists());
+
+        let storage = Storage::load_from(path, &credentials()).unwrap();
+        assert_eq!(text_of(&storage, &uuid), "foo");
+    }
+
+    fn revision_texts(storage: &Storage, uuid: &Uuid) -> Vec<String> {
+        storage.revisions(uuid).unwrap().into_iter().map(|revision| revision.note.text).collect()
+    }
+
+    /// Revisions are named by millisecond, make sure the next one does not replace the last.
+    fn next_millisecond() {
+        sleep(Duration::from_millis(2));
+    }
+
+    /// Keep the note on disk as a revision taken `age` ago.
+    fn write_revision(storage: &Storage, uuid: &Uuid, age: ChronoDuration) {
+        let path = storage.note_revisions_path(uuid);
+        let timestamp = (Utc::now() - age).timestamp_millis();
+
+        create_dir_all(&path).unwrap();
+        copy(storage.path_from_uuid(uuid), path.join(timestamp.to_string())).unwrap();
+    }
+
+    #[test]
+    fn test_save_revision() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        assert!(storage.revisions(&uuid).unwrap().is_empty());
+
+        edit(&mut storage, &uuid, "bar");
+        assert_eq!(revision_texts(&storage, &uuid), vec!["foo"]);
+
+        // Edits within the interval do not add revisions, unless forced.
+        edit(&mut storage, &uuid, "baz");
+        assert_eq!(revision_texts(&storage, &uuid), vec!["foo"]);
+
+        next_millisecond();
+        storage.save_revision(&uuid, true).unwrap();
+        assert_eq!(revision_texts(&storage, &uuid), vec!["baz", "foo"]);
+
+        // Once the interval has passed, the next edit is kept again.
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        write_revision(&storage, &uuid, ChronoDuration::from_std(REVISION_INTERVAL).unwrap());
+
+        edit(&mut storage, &uuid, "bar");
+        assert_eq!(revision_texts(&storage, &uuid), vec!["foo", "foo"]);
+    }
+
+    #[test]
+    fn test_prune_revisions() {
+        let dir = TempDir::new().unwrap();
+        let (storage, uuid) = make_storage(&dir);
+        let max_age = ChronoDuration::from_std(MAX_REVISION_AGE).unwrap();
+
+        write_revision(&storage, &uuid, max_age + ChronoDuration::days(1));
+        write_revision(&storage, &uuid, max_age - ChronoDuration::days(1));
+        storage.prune_revisions(&uuid).unwrap();
+
+        let revisions = storage.revisions(&uuid).unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert!(revisions[0].created_at > Utc::now() - max_age);
+
+        for minutes in 0..MAX_REVISIONS as i64 + 10 {
+            write_revision(&storage, &uuid, ChronoDuration::minutes(minutes));
+        }
+
+        storage.prune_revisions(&uuid).unwrap();
+
+        // The newest ones are kept.
+        let revisions = storage.revisions(&uuid).unwrap();
+        assert_eq!(revisions.len(), MAX_REVISIONS);
+        assert!(revisions.last().unwrap().created_at > Utc::now() - ChronoDuration::minutes(MAX_REVISIONS as i64));
+    }
+
+    #[test]
+    fn test_restore_revision() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        edit(&mut storage, &uuid, "bar");
+
+        // Unsaved edits are part of the version that is replaced.
+        storage.set_text("unsaved").unwrap();
+        next_millisecond();
+
+        let revision = storage.revisions(&uuid).unwrap().remove(0);
+        storage.restore_revision(&revision).unwrap();
+        assert_eq!(text_of(&storage, &uuid), "foo");
+        assert_eq!(revision_texts(&storage, &uuid), vec!["unsaved", "foo"]);
+
+        let storage = Storage::load_from(storage.path.clone(), &credentials()).unwrap();
+        assert_eq!(text_of(&storage, &uuid), "foo");
+    }
+
+    #[test]
+    fn test_revisions_after_password_change() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+        edit(&mut storage, &uuid, "bar");
+
+        let new_credentials = storage.stage_password_change("new secret").unwrap();
+        storage.commit_password_change(&new_credentials).unwrap();
+        finish_password_change_at(&path).unwrap();
+        assert_eq!(revision_texts(&storage, &uuid), vec!["foo"]);
+
+        let storage = Storage::load_from(path.clone(), &new_credentials).unwrap();
+        assert_eq!(revision_texts(&storage, &uuid), vec!["foo"]);
+
+        // Revisions are not readable with the old password anymore.
+        let storage = Storage::load_from(path, &credentials()).unwrap();
+        assert!(storage.revisions(&uuid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_with_server() {
+        let server = Server::start_local().unwrap();
+        let pending = Some("new secret".to_string());
+
+        // The server cannot be asked, so the change is kept for the next attempt.
+        let dir = TempDir::new().unwrap();
+        let (storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+        storage.stage_password_change("new secret").unwrap();
+
+        let recovered = recover_password_change_at(&path, &credentials(), pending.clone(), Some(&unreachable_url())).unwrap();
+        assert!(matches!(recovered, Recovery::Deferred));
+        assert!(staging_path(&path).exists());
+        assert!(staged_change_path(&path).exists());
+
+        // The server still has the old password.
+        Client::new_register(&server.url(), credentials()).unwrap();
+
+        let recovered = recover_password_change_at(&path, &credentials(), pending.clone(), Some(&server.url())).unwrap();
+        assert!(matches!(recovered, Recovery::RolledBack));
+        assert!(!staging_path(&path).exists());
+        assert_eq!(text_of(&Storage::load_from(path, &credentials()).unwrap(), &uuid), "foo");
+
+        // The server has accepted the new password.
+        let server = Server::start_local().unwrap();
+        let dir = TempDir::new().unwrap();
+        let (storage, uuid) = make_storage(&dir);
+        let path = storage.path.clone();
+        let new_credentials = storage.stage_password_change("new secret").unwrap();
+
+        let mut client = Client::new_register(&server.url(), credentials()).unwrap();
+        client.change_password(&new_credentials).unwrap();
+
+        let recovered = match recover_password_change_at(&path, &credentials(), pending, Some(&server.url())).unwrap() {
+            Recovery::Completed(credentials) => credentials,
+            _ => panic!("Password change has not been completed"),
+        };
+        finish_password_change_at(&path).unwrap();
+        assert_eq!(text_of(&Storage::load_from(path, &recovered).unwrap(), &uuid), "foo");
+    }
+
+    fn conflict(kind: ConflictType, server_item: Option<Envelope>, unsaved_item: Option<Envelope>) -> SyncResult {
+        SyncResult {
+            retrieved: Vec::new(),
+            saved: Vec::new(),
+            conflicts: vec![remote::Conflict { kind, server_item, unsaved_item }],
+            sync_token: None,
+        }
+    }
+
+    #[test]
+    fn test_sync_conflict() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+
+        let mut theirs = match &storage.items[&uuid] {
+            Item::Note(note) => conflicted_copy(note),
+            _ => unreachable!(),
+        };
+
+        theirs.uuid = uuid;
+        theirs.text = "bar".to_string();
+        let server_item = Item::Note(theirs).encrypt(&storage.crypto).unwrap();
+
+        let uuids = storage.resolve_conflicts(&conflict(ConflictType::SyncConflict, Some(server_item), None)).unwrap();
+
+        assert!(uuids.contains(&uuid));
+        assert_eq!(uuids.len(), 2);
+        assert_eq!(text_of(&storage, &uuid), "bar");
+
+        let copy = uuids.iter().find(|copy| **copy != uuid).unwrap();
+        assert_eq!(text_of(&storage, copy), "foo");
+        assert_eq!(storage.current, Some(*copy));
+    }
+
+    #[test]
+    fn test_uuid_conflict() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, uuid) = make_storage(&dir);
+        let tag = storage.tag_note(&uuid, "bar").unwrap();
+
+        let unsaved_item = storage.items[&uuid].encrypt(&storage.crypto).unwrap();
+        let uuids = storage.resolve_conflicts(&conflict(ConflictType::UuidConflict, None, Some(unsaved_item))).unwrap();
+
+        let new_uuid = storage.current.unwrap();
+        assert_ne!(new_uuid, uuid);
+        assert!(!storage.items.contains_key(&uuid));
+        assert_eq!(text_of(&storage, &new_uuid), "foo");
+
+        assert!(uuids.contains(&uuid));
+        assert!(uuids.contains(&new_uuid));
+        assert!(uuids.contains(&tag));
+        assert_eq!(storage.notes_of(&tag), vec![new_uuid].into_iter().collect());
+    }
+
+    #[test]
+    fn test_keep_undecryptable_items() {
+        let dir = TempDir::new().unwrap();
+        let (mut storage, _) = make_storage(&dir);
+
+        let mut other = credentials();
+        other.password = "other secret".to_string();
+
+        let uuid = Uuid::new_v4();
+        let item = Item::Tag(Tag::new("foo")).with_uuid(uuid).encrypt(&Crypto::new(&other).unwrap()).unwrap();
+
+        let result = SyncResult {
+            retrieved: vec![item],
+            saved: Vec::new(),
+            conflicts: Vec::new(),
+            sync_token: Some("token".to_string()),
+        };
+
+        assert!(storage.apply_sync(result).unwrap().is_empty());
+        assert!(storage.pending.contains_key(&uuid));
+        assert_eq!(storage.read_sync_token().unwrap().as_deref(), Some("token"));
+
+        // The item is kept on disk and does not keep the storage from loading.
+        let storage = Storage::load_from(storage.path.clone(), &credentials()).unwrap();
+        assert!(storage.pending.contains_key(&uuid));
+        assert!(!storage.items.contains_key(&uuid));
+    }
+
+    fn remote_credentials(identifier: &str) -> Credentials {
+        Credentials {
+            identifier: identifier.to_string(),
+            ..credentials()
+        }
+    }
+
+    /// Open the storage of an account in `dir` that is synced with a remote.
+    fn remote_storage(dir: &TempDir, credentials: &Credentials) -> Storage {
+        let mut storage = Storage::load_from(dir.path().join("items"), credentials).unwrap();
+        storage.connect().unwrap();
+        storage
+    }
+
+    /// Sync the way the worker does and return the uuids of changed items.
+    fn sync(storage: &mut Storage, client: &mut Client) -> Result<Vec<Uuid>> {
+        let (items, sync_token) = storage.sync_request(true).unwrap();
+        client.set_sync_token(sync_token);
+
+        match client.sync(items) {
+            Ok(result) => storage.apply_sync(result),
+            Err(err) => {
+                storage.sync_failed(None);
+                Err(err)
+            }
+        }
+    }
+
+    fn edit(storage: &mut Storage, uuid: &Uuid, text: &str) {
+        storage.set_current_uuid(uuid).unwrap();
+        storage.set_text(text).unwrap();
+        storage.flush_dirty().unwrap();
+    }
+
+    /// Address of a server that is not running anymore.
+    fn unreachable_url() -> String {
+        Server::start_local().unwrap().url()
+    }
+
+    #[test]
+    fn test_sync_with_server() {
+        let server = Server::start_local().unwrap();
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+        let mut first_client = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+        let mut first = remote_storage(&first_dir, &first_client.credentials);
+        let uuid = first.create_note();
+        edit(&mut first, &uuid, "foo");
+
+        sync(&mut first, &mut first_client).unwrap();
+        assert!(first.outbox.is_empty());
+
+        // The token survives a restart, so we continue where we left off.
+        let token = first.sync_token.clone();
+        assert!(token.is_some());
+        let mut first = Storage::load_from(first.path.clone(), &first_client.credentials).unwrap();
+        assert_eq!(first.sync_token, token);
+        assert!(first.outbox.is_empty());
+
+        let mut second_client = Client::new_sign_in(&server.url(), &remote_credentials("foo@bar.com")).unwrap();
+        let mut second = remote_storage(&second_dir, &second_client.credentials);
+        assert_eq!(sync(&mut second, &mut second_client).unwrap(), vec![uuid]);
+        assert_eq!(text_of(&second, &uuid), "foo");
+
+        let other = first.create_note();
+        edit(&mut first, &other, "other");
+        edit(&mut first, &uuid, "bar");
+        sync(&mut first, &mut first_client).unwrap();
+
+        // Only items changed since the last sync are retrieved.
+        let mut uuids = sync(&mut second, &mut second_client).unwrap();
+        uuids.sort();
+        let mut expected = vec![uuid, other];
+        expected.sort();
+        assert_eq!(uuids, expected);
+        assert_eq!(text_of(&second, &uuid), "bar");
+        assert!(sync(&mut second, &mut second_client).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_outbox_backoff() {
+        let server = Server::start_local().unwrap();
+        let dir = TempDir::new().unwrap();
+
+        let mut client = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+        let mut offline = Client::from_session(&unreachable_url(), &client.credentials, client.session().clone());
+        let mut storage = remote_storage(&dir, &client.credentials);
+        let uuid = storage.create_note();
+        edit(&mut storage, &uuid, "foo");
+
+        let err = sync(&mut storage, &mut offline).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::Network(_))));
+
+        // Changes are kept and retried after an increasing delay, only pulling is allowed.
+        assert!(storage.outbox.contains_key(&uuid));
+        assert!(storage.retry_in().unwrap() > MIN_BACKOFF / 2);
+        assert!(storage.sync_request(false).is_none());
+
+        storage.sync_failed(None);
+        assert!(storage.retry_in().unwrap() > MIN_BACKOFF);
+
+        // The outbox survives a restart and is uploaded right away.
+        let mut storage = Storage::load_from(storage.path.clone(), &client.credentials).unwrap();
+        storage.connect().unwrap();
+        assert!(storage.outbox.contains_key(&uuid));
+        assert_eq!(storage.retry_in(), Some(Duration::from_secs(0)));
+
+        sync(&mut storage, &mut client).unwrap();
+        assert!(storage.outbox.is_empty());
+        assert!(storage.retry_in().is_none());
+        assert!(Storage::load_from(storage.path.clone(), &client.credentials).unwrap().outbox.is_empty());
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let server = Server::start_local().unwrap();
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+        let mut first_client = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+        let mut second_client = Client::new_sign_in(&server.url(), &remote_credentials("foo@bar.com")).unwrap();
+        let mut first = remote_storage(&first_dir, &first_client.credentials);
+        let mut second = remote_storage(&second_dir, &second_client.credentials);
+
+        let uuid = first.create_note();
+        edit(&mut first, &uuid, "foo");
+        sync(&mut first, &mut first_client).unwrap();
+        sync(&mut second, &mut second_client).unwrap();
+        assert!(second.items.contains_key(&uuid));
+
+        first.delete(&uuid).unwrap();
+        sync(&mut first, &mut first_client).unwrap();
+
+        assert_eq!(sync(&mut second, &mut second_client).unwrap(), vec![uuid]);
+        assert!(!second.items.contains_key(&uuid));
+        assert!(!second.path_from_uuid(&uuid).exists());
+    }
+
+    #[test]
+    fn test_conflicts_with_server() {
+        let server = Server::start_local().unwrap();
+        let (first_dir, second_dir, other_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+
+        let mut first_client = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+        let mut second_client = Client::new_sign_in(&server.url(), &remote_credentials("foo@bar.com")).unwrap();
+        let mut first = remote_storage(&first_dir, &first_client.credentials);
+        let mut second = remote_storage(&second_dir, &second_client.credentials);
+
+        let uuid = first.create_note();
+        edit(&mut first, &uuid, "foo");
+        sync(&mut first, &mut first_client).unwrap();
+        sync(&mut second, &mut second_client).unwrap();
+
+        // The second edit is uploaded first, so the earlier one conflicts.
+        edit(&mut second, &uuid, "theirs");
+        sleep(Duration::from_millis(10));
+        edit(&mut first, &uuid, "ours");
+        sync(&mut first, &mut first_client).unwrap();
+
+        let uuids = sync(&mut second, &mut second_client).unwrap();
+        assert!(uuids.contains(&uuid));
+        assert_eq!(text_of(&second, &uuid), "ours");
+
+        let copy = uuids.iter().find(|copy| **copy != uuid).unwrap();
+        assert_eq!(text_of(&second, copy), "theirs");
+
+        // Another account cannot take the uuid, so the note is moved.
+        let mut other_client = Client::new_register(&server.url(), remote_credentials("bar@foo.com")).unwrap();
+        let mut other = remote_storage(&other_dir, &other_client.credentials);
+        let mut note = conflicted_copy(match &first.items[&uuid] {
+            Item::Note(note) => note,
+            _ => unreachable!(),
+        });
+        note.uuid = uuid;
+        other.items.insert(uuid, Item::Note(note));
+        other.dirty.insert(uuid);
+        other.flush_dirty().unwrap();
+
+        let uuids = sync(&mut other, &mut other_client).unwrap();
+        assert!(uuids.contains(&uuid));
+        assert!(!other.items.contains_key(&uuid));
+
+        let moved = *uuids.iter().find(|moved| **moved != uuid).unwrap();
+        assert_eq!(text_of(&other, &moved), "ours");
+
+        sync(&mut other, &mut other_client).unwrap();
+        assert!(other.outbox.is_empty());
+        assert_eq!(text_of(&first, &uuid), "ours");
+    }
+
+    #[test]
+    fn test_session_refresh() {
+        let server = Server::start_local().unwrap();
+        let dir = TempDir::new().unwrap();
+
+        let mut client = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+        let mut storage = remote_storage(&dir, &client.credentials);
+        let uuid = storage.create_note();
+        edit(&mut storage, &uuid, "foo");
+
+        // The server answers with 498, the client refreshes the session and tries again.
+        server.expire_access_tokens();
+        let session = client.session().clone();
+        sync(&mut storage, &mut client).unwrap();
+        assert!(*client.session() != session);
+        assert!(storage.outbox.is_empty());
+
+        // A session that has been ended cannot be refreshed.
+        let mut revoked = Client::from_session(&server.url(), &client.credentials, client.session().clone());
+        client.sign_out().unwrap();
+        edit(&mut storage, &uuid, "bar");
+
+        let err = sync(&mut storage, &mut revoked).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::ExpiredToken)));
+        assert!(storage.outbox.contains_key(&uuid));
+    }
+
+    #[test]
+    fn test_remote_errors() {
+        let server = Server::start_local().unwrap();
+        Client::new_register(&server.url(), remote_credentials("foo@bar.com")).unwrap();
+
+        let mut wrong = remote_credentials("foo@bar.com");
+        wrong.password = "wrong".to_string();
+        let err = Client::new_sign_in(&server.url(), &wrong).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::InvalidCredentials(_))));
+
+        let err = Client::new_register(&server.url(), remote_credentials("foo@bar.com")).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::Server { status, .. }) if status.as_u16() == 400));
+
+        let err = Client::new_sign_in(&unreachable_url(), &remote_credentials("foo@bar.com")).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RemoteError>(), Some(RemoteError::Network(_))));
     }
 }
@@ -1,15 +1,24 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use gio::prelude::*;
 use gtk::prelude::*;
 use glib::translate::{ToGlib, from_glib};
+use std::cmp;
 use std::env;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use uuid::Uuid;
 use crate::config::{Config, Geometry};
-use crate::consts::{APP_DOMAIN, APP_ID, APP_VERSION, ABOUT_UI, BASE_CSS, IMPORT_UI, SHORTCUTS_UI, WINDOW_UI};
+use crate::consts::{APP_DOMAIN, APP_ID, APP_VERSION, ABOUT_UI, BASE_CSS, IMPORT_UI, MFA_UI, PASSWORD_UI, SHORTCUTS_UI, WINDOW_UI};
 use crate::secret;
-use crate::storage::Storage;
+use crate::storage::{Recovery, Storage};
 use crate::ui::controller::Controller;
-use standardfile::{remote, Exported, Credentials};
+use crate::ui::preview::Preview;
+use crate::ui::revisions::choose_revision;
+use crate::ui::sidebar::{Sidebar, View};
+use crate::worker::{Response, Worker};
+use standardfile::{crypto, Exported, Credentials, NoteFlags, remote::{MfaCode, RemoteError, Session}};
 
 pub struct Application {
     app: gtk::Application,
@@ -23,25 +32,68 @@ pub struct Application {
     setup_login_button: gtk::Button,
     note_list_box: gtk::ListBox,
     note_popover: gtk::PopoverMenu,
+    preview: Rc<Preview>,
 }
 
 enum AppEvent {
     AddNote,
+    TrashNote,
+    RestoreNote,
     DeleteNote,
+    EmptyTrash,
+    ShowRevisions,
+    Undo,
+    HideUndo(u32),
     SelectNote,
     Register(String, Credentials),
-    SignIn(String, Credentials),
+    SignIn(String, Credentials, Option<MfaCode>),
+    Resume(String, Credentials, Session),
     Import(PathBuf, String, Option<String>),
     Export(PathBuf),
+    ChangePassword(String, String),
     Update(Option<String>, Option<String>),
     UpdateFilter(Option<String>),
+    UpdateTags(String),
+    SelectView(View),
+    SetFlag(Flag, bool),
     UpdateGeometry(Geometry),
     CreateStorage(Credentials),
     Switch(String),
     FlushDirty,
+    Pull,
+    Online,
+    Remote(String, Response),
     Quit,
 }
 
+/// Note flags that can be toggled from the note menu.
+#[derive(Clone, Copy)]
+enum Flag {
+    Pinned,
+    Archived,
+    Locked,
+}
+
+impl Flag {
+    const ALL: [Flag; 3] = [Flag::Pinned, Flag::Archived, Flag::Locked];
+
+    fn action_name(self) -> &'static str {
+        match self {
+            Flag::Pinned => "pin",
+            Flag::Archived => "archive",
+            Flag::Locked => "lock",
+        }
+    }
+
+    fn get_mut(self, flags: &mut NoteFlags) -> &mut bool {
+        match self {
+            Flag::Pinned => &mut flags.pinned,
+            Flag::Archived => &mut flags.archived,
+            Flag::Locked => &mut flags.locked,
+        }
+    }
+}
+
 fn setup_server_dialog(builder: &gtk::Builder) {
     let server_box = get_widget!(builder, gtk::ComboBoxText, "server-box");
     let server_entry = server_box.get_child().unwrap().downcast::<gtk::Entry>().unwrap();
@@ -58,20 +110,187 @@ fn get_user_details(builder: &gtk::Builder) -> Credentials {
     let identifier_entry = get_widget!(builder, gtk::Entry, "identifier-entry");
     let password_entry = get_widget!(builder, gtk::Entry, "password-entry");
 
-    Credentials::from_defaults(&identifier_entry.get_text(), &password_entry.get_text())
+    Credentials::from_defaults(&identifier_entry.get_text(), &password_entry.get_text(), crypto::DEFAULT_VERSION)
 }
 
 fn get_auth_details(builder: &gtk::Builder) -> (String, Credentials) {
     let server_combo_box = get_widget!(builder, gtk::ComboBoxText, "server-combo");
 
-    (server_combo_box.get_active_text().unwrap().to_string(), get_user_details(&builder))
+    (server_combo_box.get_active_text().unwrap().to_string(), get_user_details(builder))
+}
+
+/// Read the storage of an identity. The items key of a server-backed identity is retrieved with
+/// the first sync, so none is created for it.
+fn open_storage(credentials: &Credentials, server: &Option<String>) -> Result<Storage> {
+    match server {
+        Some(_) => Storage::open(credentials),
+        None => Storage::new(credentials),
+    }
+}
+
+/// Stage a password change. The new password is kept in the keyring as pending until the change
+/// is committed, so an interrupted change can be completed or rolled back on the next start.
+fn stage_password_change(storage: &Storage, password: &str, server: Option<&str>) -> Result<Credentials> {
+    let credentials = storage.stage_password_change(password)?;
+
+    if let Err(err) = secret::store_pending(&credentials, server) {
+        storage.abort_password_change()?;
+        return Err(err);
+    }
+
+    Ok(credentials)
+}
+
+/// Commit a staged password change the remote, if any, has accepted and store the new
+/// credentials in the keyring and the configuration. The pending password is cleared last, so if
+/// anything fails the change is completed on the next start.
+fn commit_password_change(storage: &mut Storage, config: &mut Config, credentials: &Credentials) -> Result<()> {
+    let server = config.server();
+
+    storage.commit_password_change(credentials)?;
+    secret::store(credentials, server.as_deref())?;
+    config.add(credentials, server);
+    config.write()?;
+    Storage::finish_password_change(&credentials.identifier)?;
+    secret::clear_pending(&credentials.identifier)
+}
+
+/// Roll back a staged password change.
+fn abort_password_change(storage: &Storage) -> Result<()> {
+    storage.abort_password_change()?;
+    secret::clear_pending(&storage.credentials().identifier)
+}
+
+/// Pass responses of a worker for `identifier` to the main loop.
+fn forward_responses(sender: &glib::Sender<AppEvent>, identifier: &str) -> impl Fn(Response) + Send + 'static {
+    let sender = sender.clone();
+    let identifier = identifier.to_string();
+
+    move |response| {
+        sender.send(AppEvent::Remote(identifier.clone(), response)).unwrap();
+    }
+}
+
+/// Hand pending changes to the worker. If `pull` is set, sync even if there is nothing to upload
+/// to retrieve remote changes.
+fn request_sync(storage: &mut Storage, worker: &Option<Worker>, pull: bool) {
+    if let Some(worker) = worker {
+        if let Some((items, sync_token)) = storage.sync_request(pull) {
+            if let Err(err) = worker.sync(items, sync_token) {
+                g_warning!(APP_DOMAIN, "{}", err);
+                storage.sync_failed(None);
+            }
+        }
+    }
+}
+
+/// Titles of the comma-separated tags in `text`, without duplicates.
+fn parse_tags(text: &str) -> Vec<String> {
+    let mut titles: Vec<String> = Vec::new();
+
+    for title in text.split(',').map(str::trim).filter(|title| !title.is_empty()) {
+        if !titles.iter().any(|t| t == title) {
+            titles.push(title.to_string());
+        }
+    }
+
+    titles
+}
+
+/// Tag and untag the current note so that it has exactly the tags titled `titles`.
+fn update_tags(storage: &mut Storage, titles: &[String]) -> Result<()> {
+    let uuid = storage.current.ok_or_else(|| anyhow!("No note selected"))?;
+
+    let removed = storage
+        .tags_of(&uuid)
+        .iter()
+        .filter(|tag| !titles.contains(&tag.title))
+        .map(|tag| tag.uuid)
+        .collect::<Vec<_>>();
+
+    for tag in removed {
+        storage.untag_note(&uuid, &tag)?;
+    }
+
+    for title in titles {
+        storage.tag_note(&uuid, title)?;
+    }
+
+    Ok(())
+}
+
+/// Only show the notes of `view` in the note list.
+fn show_view(view: View, controller: &Controller, storage: &Storage) {
+    let notes = match view {
+        View::Tag(tag) => storage.notes_of(&tag),
+        _ => HashSet::new(),
+    };
+
+    controller.filter_view(view, notes);
+}
+
+/// Show the tags of `storage` in the sidebar and only the notes of the selected view.
+fn show_tags(sidebar: &Sidebar, controller: &Controller, storage: &Storage) {
+    sidebar.set_tags(&storage.tags(), |tag| storage.notes_of(&tag.uuid).len());
+    show_view(sidebar.selected(), controller, storage);
+}
+
+/// Reflect the flags of the selected note in the note menu. Locked notes cannot be edited.
+fn show_flags(app: &gtk::Application, title_entry: &gtk::Entry, text_view: &gtk::TextView, flags: &NoteFlags) {
+    let mut flags = *flags;
+
+    for flag in Flag::ALL.iter() {
+        let action = app.lookup_action(flag.action_name()).and_then(|action| action.downcast::<gio::SimpleAction>().ok());
+
+        if let Some(action) = action {
+            action.set_state(&flag.get_mut(&mut flags).to_variant());
+        }
+    }
+
+    let enabled = [("delete", !flags.trashed), ("restore", flags.trashed), ("delete-permanently", flags.trashed)];
+
+    for (name, enabled) in enabled.iter() {
+        let action = app.lookup_action(name).and_then(|action| action.downcast::<gio::SimpleAction>().ok());
+
+        if let Some(action) = action {
+            action.set_enabled(*enabled);
+        }
+    }
+
+    title_entry.set_editable(!flags.locked);
+    text_view.set_editable(!flags.locked);
+}
+
+/// Ask for the code of the second factor. Returns `None` if the dialog was cancelled.
+fn ask_mfa_code(window: &gtk::ApplicationWindow, message: &str) -> Option<String> {
+    let builder = gtk::Builder::from_resource(MFA_UI);
+    let dialog = get_widget!(builder, gtk::Dialog, "mfa-dialog");
+    let label = get_widget!(builder, gtk::Label, "mfa-label");
+    let code_entry = get_widget!(builder, gtk::Entry, "mfa-code");
+
+    if !message.is_empty() {
+        label.set_text(message);
+    }
+
+    dialog.set_transient_for(Some(window));
+    dialog.set_modal(true);
+
+    let code = match dialog.run() {
+        gtk::ResponseType::Ok => Some(code_entry.get_text().trim().to_string()),
+        _ => None,
+    };
+
+    dialog.close();
+    code.filter(|code| !code.is_empty())
 }
 
 fn show_header_buttons(builder: &gtk::Builder, visible: bool) {
     let menu_button = get_widget!(builder, gtk::MenuButton, "appmenu-button");
     let add_button = get_widget!(builder, gtk::Button, "add-button");
+    let preview_button = get_widget!(builder, gtk::ToggleButton, "preview-button");
     menu_button.set_visible(visible);
     add_button.set_visible(visible);
+    preview_button.set_visible(visible);
 }
 
 fn show_setup_content(builder: &gtk::Builder) {
@@ -93,7 +312,7 @@ fn show_notification(builder: &gtk::Builder, message: &str) {
     let label = get_widget!(builder, gtk::Label, "notification-label");
     let close_button = get_widget!(builder, gtk::Button, "notification-button");
 
-    label.set_text(&message);
+    label.set_text(message);
     revealer.set_reveal_child(true);
 
     close_button.connect_clicked(move |_| {
@@ -101,6 +320,94 @@ fn show_notification(builder: &gtk::Builder, message: &str) {
     });
 }
 
+/// Ask whether to go ahead with something that cannot be undone.
+fn confirm(window: &gtk::ApplicationWindow, message: &str, action: &str) -> bool {
+    let dialog = gtk::MessageDialog::new(Some(window), gtk::DialogFlags::MODAL, gtk::MessageType::Question, gtk::ButtonsType::None, message);
+    dialog.add_button("Cancel", gtk::ResponseType::Cancel);
+    dialog.add_button(action, gtk::ResponseType::Accept);
+
+    if let Some(button) = dialog.get_widget_for_response(gtk::ResponseType::Accept) {
+        button.get_style_context().add_class("destructive-action");
+    }
+
+    let response = dialog.run();
+    dialog.close();
+    response == gtk::ResponseType::Accept
+}
+
+/// Show the undo toast and hide it again after a few seconds unless another note is trashed in
+/// the meantime.
+fn show_undo(builder: &gtk::Builder, sender: &glib::Sender<AppEvent>, generation: u32) {
+    get_widget!(builder, gtk::Revealer, "undo-revealer").set_reveal_child(true);
+
+    glib::source::timeout_add_seconds(5,
+        clone!(@strong sender => move || {
+            sender.send(AppEvent::HideUndo(generation)).unwrap();
+            glib::Continue(false)
+        })
+    );
+}
+
+fn hide_undo(builder: &gtk::Builder) {
+    get_widget!(builder, gtk::Revealer, "undo-revealer").set_reveal_child(false);
+}
+
+/// Move a note to the trash or restore it and update the note list and sidebar.
+fn set_trashed(storage: &mut Storage, controller: &mut Controller, sidebar: &Sidebar, uuid: &Uuid, trashed: bool) -> Result<()> {
+    storage.set_trashed(uuid, trashed)?;
+
+    if let Some(item) = storage.items.get(uuid) {
+        controller.update(item);
+    }
+
+    show_tags(sidebar, controller, storage);
+    Ok(())
+}
+
+/// Send a `FlushDirty` event after `delay`.
+fn schedule_flush(sender: &glib::Sender<AppEvent>, delay: Duration) {
+    let seconds = cmp::max(delay.as_secs(), 1) as u32;
+
+    glib::source::timeout_add_seconds(seconds,
+        clone!(@strong sender => move || {
+            sender.send(AppEvent::FlushDirty).unwrap();
+            glib::Continue(false)
+        })
+    );
+}
+
+/// Replace title and text in the editor without emitting the change signals, which would
+/// store them again.
+fn show_note_contents(title_entry: &gtk::Entry, text_buffer: &gtk::TextBuffer, title_handler: Option<u64>, text_handler: Option<u64>, title: &str, text: &str) {
+    if let Some(handler) = title_handler {
+        title_entry.block_signal(&from_glib(handler));
+    }
+
+    if let Some(handler) = text_handler {
+        text_buffer.block_signal(&from_glib(handler));
+    }
+
+    title_entry.set_text(title);
+    text_buffer.set_text(text);
+
+    if let Some(handler) = title_handler {
+        title_entry.unblock_signal(&from_glib(handler));
+    }
+
+    if let Some(handler) = text_handler {
+        text_buffer.unblock_signal(&from_glib(handler));
+    }
+}
+
+/// Show how many items the initial sync has retrieved.
+fn show_sync_progress(builder: &gtk::Builder, count: usize) {
+    let revealer = get_widget!(builder, gtk::Revealer, "notification-revealer");
+    let label = get_widget!(builder, gtk::Label, "notification-label");
+
+    label.set_text(&format!("Retrieved {} items …", count));
+    revealer.set_reveal_child(true);
+}
+
 impl Application {
     fn setup_overlay_help(&self) {
         let builder = gtk::Builder::from_resource(SHORTCUTS_UI);
@@ -146,7 +453,54 @@ impl Application {
 
         action!(self.app, "delete",
             clone!(@strong self.sender as sender => move |_, _| {
-                sender.send(AppEvent::DeleteNote).unwrap();
+                sender.send(AppEvent::TrashNote).unwrap();
+            })
+        );
+
+        action!(self.app, "restore",
+            clone!(@strong self.sender as sender => move |_, _| {
+                sender.send(AppEvent::RestoreNote).unwrap();
+            })
+        );
+
+        action!(self.app, "delete-permanently",
+            clone!(@strong self.sender as sender, @weak self.window as window => move |_, _| {
+                if confirm(&window, "Delete the note permanently?", "Delete") {
+                    sender.send(AppEvent::DeleteNote).unwrap();
+                }
+            })
+        );
+
+        action!(self.app, "revisions",
+            clone!(@strong self.sender as sender => move |_, _| {
+                sender.send(AppEvent::ShowRevisions).unwrap();
+            })
+        );
+
+        action!(self.app, "empty-trash",
+            clone!(@strong self.sender as sender, @weak self.window as window => move |_, _| {
+                if confirm(&window, "Delete all notes in the trash permanently?", "Empty Trash") {
+                    sender.send(AppEvent::EmptyTrash).unwrap();
+                }
+            })
+        );
+
+        for flag in Flag::ALL.iter().copied() {
+            toggle_action!(self.app, flag.action_name(),
+                clone!(@strong self.sender as sender => move |_, value| {
+                    if let Some(value) = value.and_then(|value| value.get::<bool>()) {
+                        sender.send(AppEvent::SetFlag(flag, value)).unwrap();
+                    }
+                })
+            );
+        }
+
+        toggle_action!(self.app, "preview",
+            clone!(@strong self.preview as preview => move |action, value| {
+                if let Some(visible) = value.and_then(|value| value.get::<bool>()) {
+                    action.set_state(&visible.to_variant());
+                    preview.set_visible(visible);
+                }
             })
         );
 
@@ -178,21 +532,44 @@ impl Application {
                 dialog.set_transient_for(Some(&window));
                 dialog.set_modal(true);
 
-                match dialog.run() {
-                    gtk::ResponseType::Ok => {
-                        let file_chooser = get_widget!(builder, gtk::FileChooserButton, "import-file-button");
+                if dialog.run() == gtk::ResponseType::Ok {
+                    let file_chooser = get_widget!(builder, gtk::FileChooserButton, "import-file-button");
 
-                        if let Some(filename) = file_chooser.get_filename() {
-                            let password_entry = get_widget!(builder, gtk::Entry, "import-password");
-                            let server_box = get_widget!(builder, gtk::ComboBoxText, "server-box");
-                            let server_entry = server_box.get_child().unwrap().downcast::<gtk::Entry>().unwrap();
-                            let server = server_entry.get_text().to_string();
-                            let server = if server != "" { Some(server) } else { None };
+                    if let Some(filename) = file_chooser.get_filename() {
+                        let password_entry = get_widget!(builder, gtk::Entry, "import-password");
+                        let server_box = get_widget!(builder, gtk::ComboBoxText, "server-box");
+                        let server_entry = server_box.get_child().unwrap().downcast::<gtk::Entry>().unwrap();
+                        let server = server_entry.get_text().to_string();
+                        let server = if !server.is_empty() { Some(server) } else { None };
 
-                            sender.send(AppEvent::Import(filename, password_entry.get_text().to_string(), server)).unwrap();
-                        }
+                        sender.send(AppEvent::Import(filename, password_entry.get_text().to_string(), server)).unwrap();
+                    }
+                }
+
+                dialog.close();
+            })
+        );
+
+        action!(self.app, "change-password",
+            clone!(@weak self.window as window, @weak self.builder as main_builder, @strong self.sender as sender => move |_, _| {
+                let builder = gtk::Builder::from_resource(PASSWORD_UI);
+                let dialog = get_widget!(builder, gtk::Dialog, "password-dialog");
+
+                dialog.set_transient_for(Some(&window));
+                dialog.set_modal(true);
+
+                if dialog.run() == gtk::ResponseType::Ok {
+                    let current_entry = get_widget!(builder, gtk::Entry, "current-password");
+                    let new_entry = get_widget!(builder, gtk::Entry, "new-password");
+                    let confirm_entry = get_widget!(builder, gtk::Entry, "confirm-password");
+                    let new_password = new_entry.get_text().to_string();
+
+                    if new_password.is_empty() || new_password != confirm_entry.get_text().as_str() {
+                        show_notification(&main_builder, "New passwords do not match.");
+                    }
+                    else {
+                        sender.send(AppEvent::ChangePassword(current_entry.get_text().to_string(), new_password)).unwrap();
                     }
-                    _ => {}
                 }
 
                 dialog.close();
@@ -208,13 +585,10 @@ impl Application {
                     &[("_Cancel", gtk::ResponseType::Cancel), ("_Save", gtk::ResponseType::Accept)]
                 );
 
-                match dialog.run() {
-                    gtk::ResponseType::Accept => {
-                        if let Some(filename) = dialog.get_filename() {
-                            sender.send(AppEvent::Export(filename)).unwrap();
-                        }
-                    },
-                    _ => {}
+                if dialog.run() == gtk::ResponseType::Accept {
+                    if let Some(filename) = dialog.get_filename() {
+                        sender.send(AppEvent::Export(filename)).unwrap();
+                    }
                 }
 
                 dialog.close();
@@ -224,6 +598,7 @@ impl Application {
         self.app.set_accels_for_action("app.quit", &["<primary>q"]);
         self.app.set_accels_for_action("app.search", &["<primary>f"]);
         self.app.set_accels_for_action("app.tags", &["<primary>t"]);
+        self.app.set_accels_for_action("app.preview", &["<primary>p"]);
     }
 
     fn setup_signals(&self) {
@@ -244,6 +619,18 @@ impl Application {
 
         self.search_bar.connect_entry(&search_entry);
 
+        get_widget!(self.builder, gtk::Button, "undo-button").connect_clicked(
+            clone!(@strong self.sender as sender => move |_| {
+                sender.send(AppEvent::Undo).unwrap();
+            })
+        );
+
+        self.tag_entry.connect_activate(
+            clone!(@strong self.sender as sender => move |entry| {
+                sender.send(AppEvent::UpdateTags(entry.get_text().to_string())).unwrap();
+            })
+        );
+
         self.app.connect_activate(
             clone!(@weak self.window as window => move |app| {
                 window.set_application(Some(app));
@@ -252,6 +639,14 @@ impl Application {
             })
         );
 
+        gio::NetworkMonitor::get_default().unwrap().connect_network_changed(
+            clone!(@strong self.sender as sender => move |_, available| {
+                if available {
+                    sender.send(AppEvent::Online).unwrap();
+                }
+            })
+        );
+
         self.window.connect_destroy(
             clone!(@strong self.sender as sender => move |_| {
                 sender.send(AppEvent::Quit).unwrap();
@@ -264,10 +659,10 @@ impl Application {
                 let (x, y) = window.get_position();
 
                 sender.send(AppEvent::UpdateGeometry(Geometry {
-                    x: x,
-                    y: y,
-                    width: width,
-                    height: height,
+                    x,
+                    y,
+                    width,
+                    height,
                     maximized: false,
                 })).unwrap();
 
@@ -292,7 +687,7 @@ impl Application {
         self.setup_login_button.connect_clicked(
             clone!(@strong self.builder as builder, @strong self.sender as sender => move |_| {
                 let (server, credentials) = get_auth_details(&builder);
-                sender.send(AppEvent::SignIn(server, credentials)).unwrap();
+                sender.send(AppEvent::SignIn(server, credentials, None)).unwrap();
             })
         );
 
@@ -362,9 +757,17 @@ impl Application {
             setup_login_button: get_widget!(builder, gtk::Button, "login-button"),
             note_list_box: note_list_box.clone(),
             note_popover: note_popover.clone(),
+            preview: Preview::new(&builder),
         };
 
         let mut controller = Controller::new(&builder);
+        let sidebar = Sidebar::new(&builder, &application.tag_entry);
+
+        sidebar.connect_selected(
+            clone!(@strong sender => move |view| {
+                sender.send(AppEvent::SelectView(view)).unwrap();
+            })
+        );
         let mut config = Config::new()?;
 
         for identifier in config.identifiers() {
@@ -381,29 +784,51 @@ impl Application {
             );
         }
 
-        let mut storage = match &config.identifier() {
+        let mut storage = match config.identifier().cloned() {
             Some(identifier) => {
                 if let Some(geometry) = &config.geometry {
-                    application.restore_geometry(&geometry);
+                    application.restore_geometry(geometry);
                 }
 
                 let server = config.server();
+
+                if let Some(pending) = secret::load_pending(&identifier, &server)? {
+                    let credentials = config.credentials()?;
+                    match Storage::recover_password_change(&credentials, Some(pending), server.as_deref())? {
+                        Recovery::Completed(credentials) => {
+                            secret::store(&credentials, server.as_deref())?;
+                            config.add(&credentials, server.clone());
+                            config.write()?;
+                            Storage::finish_password_change(&identifier)?;
+                            secret::clear_pending(&identifier)?;
+                        }
+                        Recovery::RolledBack => secret::clear_pending(&identifier)?,
+                        // Keep the new password until we know if the server accepted it.
+                        Recovery::Deferred => {}
+                    }
+                }
+
                 let password = secret::load(&identifier, &server)?;
-                let credentials = Credentials::from_defaults(&identifier, &password);
+                let credentials = Credentials::from_defaults(&identifier, &password, crypto::DEFAULT_VERSION);
 
                 if let Some(server) = server {
-                    sender.send(AppEvent::SignIn(server.to_string(), credentials)).unwrap();
+                    // Resume the previous session instead of signing in with the password again.
+                    match secret::load_session(&identifier, &Some(server.clone()))? {
+                        Some(session) => sender.send(AppEvent::Resume(server, config.credentials()?, session)).unwrap(),
+                        None => sender.send(AppEvent::SignIn(server, credentials, None)).unwrap(),
+                    }
                 }
 
                 show_main_content(&builder);
 
                 let credentials = config.credentials()?;
-                let storage = Storage::new(&credentials, None)?;
+                let storage = open_storage(&credentials, &config.server())?;
 
                 for item in storage.items.values() {
-                    controller.insert(&item);
+                    controller.insert(item);
                 }
 
+                show_tags(&sidebar, &controller, &storage);
                 controller.select_first();
 
                 Some(storage)
@@ -417,13 +842,39 @@ impl Application {
         application.setup_signals();
         application.setup_binds();
 
+        if config.sync_interval > 0 {
+            glib::source::timeout_add_seconds(config.sync_interval,
+                clone!(@strong sender => move || {
+                    sender.send(AppEvent::Pull).unwrap();
+                    glib::Continue(true)
+                })
+            );
+        }
+
+        let mut worker: Option<Worker> = None;
+        let mut initial_sync = false;
         let mut flush_timer_running = false;
         let mut title_entry_handler: Option<u64> = None;
         let mut text_buffer_handler: Option<u64> = None;
+        let mut last_trashed: Option<Uuid> = None;
+        let mut undo_generation: u32 = 0;
+        let tag_entry = application.tag_entry.clone();
 
         receiver.attach(None,
             clone!(@strong sender, @strong app, @strong window => move |event| {
                 match event {
+                    AppEvent::Pull => {
+                        if let Some(storage) = &mut storage {
+                            request_sync(storage, &worker, true);
+                        }
+                    }
+                    AppEvent::Online => {
+                        if let Some(storage) = &mut storage {
+                            storage.reset_backoff();
+                        }
+
+                        sender.send(AppEvent::FlushDirty).unwrap();
+                    }
                     AppEvent::Quit => {
                         if let Some(storage) = &mut storage {
                             if let Err(err) = storage.flush_dirty() {
@@ -441,11 +892,12 @@ impl Application {
                         config.geometry = Some(geometry);
                     }
                     AppEvent::CreateStorage(user) => {
-                        let credentials = Credentials::from_defaults(&user.identifier, &user.password);
+                        let credentials = Credentials::from_defaults(&user.identifier, &user.password, crypto::DEFAULT_VERSION);
 
-                        match Storage::new(&credentials, None) {
+                        match Storage::new(&credentials) {
                             Ok(s) => {
                                 storage = Some(s);
+                                worker = None;
                                 config.add(&credentials, None);
                                 if let Err(err) = secret::store(&credentials, None) {
                                     show_notification(&builder, &format!("{}", err));
@@ -454,6 +906,10 @@ impl Application {
                                     controller.clear();
                                     show_main_content(&builder);
                                 }
+
+                                if let Some(storage) = &storage {
+                                    show_tags(&sidebar, &controller, storage);
+                                }
                             }
                             Err(message) => {
                                 show_notification(&builder, &format!("Error: {}.", message));
@@ -462,59 +918,18 @@ impl Application {
                     }
                     AppEvent::Register(server, credentials) => {
                         g_info!(APP_DOMAIN, "Registering with {}", server);
-                        let client = remote::Client::new_register(&server, credentials);
-
-                        match client {
-                            Ok(client) => {
-                                let credentials = client.credentials.clone();
-                                storage = Some(Storage::new(&credentials, Some(client)).unwrap());
-
-                                if let Err(err) = secret::store(&credentials, Some(&server)) {
-                                    show_notification(&builder, &format!("{}", err));
-                                }
-                                else {
-                                    config.add(&credentials, Some(server));
-                                    show_main_content(&builder);
-                                }
-                            }
-                            Err(message) => {
-                                let message = format!("Registration failed: {}.", message);
-                                show_notification(&builder, &message);
-                            }
-                        };
+                        let notify = forward_responses(&sender, &credentials.identifier);
+                        worker = Some(Worker::register(&server, credentials, notify));
                     }
-                    AppEvent::SignIn(server, credentials) => {
+                    AppEvent::SignIn(server, credentials, mfa) => {
                         g_info!(APP_DOMAIN, "Signing in to {}", server);
-                        let client = remote::Client::new_sign_in(&server, &credentials);
-
-                        match client {
-                            Ok(client) => {
-                                // We have to use the clients credentials because encryption
-                                // parameters such as nonce and number of iterations might have
-                                // changed.
-                                let credentials = client.credentials.clone();
-
-                                // Switch storage, read local files and show them in the UI.
-                                storage = Some(Storage::new(&credentials, Some(client)).unwrap());
-
-                                for item in storage.as_ref().unwrap().items.values() {
-                                    controller.insert(&item);
-                                }
-
-                                // Store the encryption password and auth token in the keyring.
-                                if let Err(err) = secret::store(&credentials, Some(&server)) {
-                                    show_notification(&builder, &format!("{}", err));
-                                }
-                                else {
-                                    config.add(&credentials, Some(server));
-                                    show_main_content(&builder);
-                                }
-                            }
-                            Err(message) => {
-                                let message = format!("Login failed: {}.", message);
-                                show_notification(&builder, &message);
-                            }
-                        }
+                        let notify = forward_responses(&sender, &credentials.identifier);
+                        worker = Some(Worker::sign_in(&server, credentials, mfa, notify));
+                    }
+                    AppEvent::Resume(server, credentials, session) => {
+                        g_info!(APP_DOMAIN, "Resuming session with {}", server);
+                        let notify = forward_responses(&sender, &credentials.identifier);
+                        worker = Some(Worker::resume(&server, credentials, session, notify));
                     }
                     AppEvent::Import(path, password, server) => {
                         let filename = path.file_name().unwrap().to_string_lossy();
@@ -527,7 +942,6 @@ impl Application {
                                     show_notification(&builder, &format!("{}", err));
                                 }
 
-                                config.add(&credentials, server);
                                 let new_storage = Storage::new_from_items(&credentials, &exported.items);
 
                                 match new_storage {
@@ -536,11 +950,17 @@ impl Application {
                                         show_notification(&builder, &message);
                                     }
                                     Ok(s) => {
+                                        // Legacy imports are migrated and thus come with new
+                                        // encryption parameters.
+                                        config.add(s.credentials(), server);
+
                                         for item in s.items.values() {
-                                            controller.insert(&item);
+                                            controller.insert(item);
                                         }
 
+                                        show_tags(&sidebar, &controller, &s);
                                         storage = Some(s);
+                                        worker = None;
                                     }
                                 }
 
@@ -555,6 +975,35 @@ impl Application {
                             show_notification(&builder, &message);
                         }
                     }
+                    AppEvent::ChangePassword(current, new) => {
+                        if let Some(storage) = &mut storage {
+                            if current != storage.credentials().password {
+                                show_notification(&builder, "Current password is wrong.");
+                            }
+                            else {
+                                let server = config.server();
+
+                                // Local storages are committed right away, otherwise we wait for
+                                // the remote to accept the new password.
+                                let result = stage_password_change(storage, &new, server.as_deref()).and_then(|credentials| {
+                                    match &worker {
+                                        Some(worker) => worker.change_password(credentials).map_err(|err| {
+                                            abort_password_change(storage).err().unwrap_or(err)
+                                        }),
+                                        None => {
+                                            commit_password_change(storage, &mut config, &credentials)?;
+                                            show_notification(&builder, "Password changed.");
+                                            Ok(())
+                                        }
+                                    }
+                                });
+
+                                if let Err(err) = result {
+                                    show_notification(&builder, &format!("Could not change password: {}.", err));
+                                }
+                            }
+                        }
+                    }
                     AppEvent::Export(path) => {
                         if let Some(storage) = &storage {
                             let exported = storage.export().unwrap();
@@ -562,33 +1011,161 @@ impl Application {
                         }
                     }
                     AppEvent::Switch(identifier) => {
-                        controller.clear();
-                        config.switch(&identifier).unwrap();
+                        let previous = config.identifier().cloned();
+                        let result = config.switch(&identifier).and_then(|_| {
+                            open_storage(&config.credentials()?, &config.server())
+                        });
 
-                        // FIXME: do something about the unwraps
-                        let credentials = config.credentials().unwrap();
-                        let new_storage = Storage::new(&credentials, None).unwrap();
+                        match result {
+                            Ok(new_storage) => {
+                                controller.clear();
 
-                        for item in new_storage.items.values() {
-                            controller.insert(&item);
-                        }
+                                for item in new_storage.items.values() {
+                                    controller.insert(item);
+                                }
+
+                                show_tags(&sidebar, &controller, &new_storage);
+                                storage = Some(new_storage);
+                                worker = None;
+                            }
+                            Err(err) => {
+                                if let Some(previous) = previous {
+                                    config.switch(&previous).ok();
+                                }
 
-                        storage = Some(new_storage);
+                                show_notification(&builder, &format!("Could not switch to {}: {}.", identifier, err));
+                            }
+                        }
                     }
                     AppEvent::AddNote => {
                         if let Some(storage) = &mut storage {
                             let uuid = storage.create_note();
+
+                            // Keep new notes visible by adding them to the selected tag.
+                            match sidebar.selected() {
+                                View::Tag(tag) => {
+                                    if let Err(err) = storage.add_to_tag(&uuid, &tag) {
+                                        g_warning!(APP_DOMAIN, "Could not tag note: {}", err);
+                                    }
+
+                                    show_tags(&sidebar, &controller, storage);
+                                    request_sync(storage, &worker, false);
+                                }
+                                View::Archived | View::Trash => sidebar.select(View::All),
+                                View::All => {}
+                            }
+
                             let item = storage.items.get(&uuid).unwrap();
+                            controller.insert(item);
+                        }
+                    }
+                    AppEvent::TrashNote => {
+                        if let Some(storage) = &mut storage {
+                            if let Some(uuid) = storage.current {
+                                g_info!(APP_DOMAIN, "Moving {} to the trash", uuid);
+
+                                match set_trashed(storage, &mut controller, &sidebar, &uuid, true) {
+                                    Ok(_) => {
+                                        controller.select_first();
+                                        request_sync(storage, &worker, false);
+
+                                        last_trashed = Some(uuid);
+                                        undo_generation = undo_generation.wrapping_add(1);
+                                        show_undo(&builder, &sender, undo_generation);
+                                    }
+                                    Err(err) => g_warning!(APP_DOMAIN, "Could not move note to the trash: {}", err),
+                                }
+                            }
+                        }
+                    }
+                    AppEvent::RestoreNote => {
+                        if let Some(storage) = &mut storage {
+                            if let Some(uuid) = storage.current {
+                                match set_trashed(storage, &mut controller, &sidebar, &uuid, false) {
+                                    Ok(_) => {
+                                        controller.select_first();
+                                        request_sync(storage, &worker, false);
+                                    }
+                                    Err(err) => g_warning!(APP_DOMAIN, "Could not restore note: {}", err),
+                                }
+                            }
+                        }
+                    }
+                    AppEvent::Undo => {
+                        hide_undo(&builder);
 
-                            controller.insert(&item);
+                        if let (Some(storage), Some(uuid)) = (&mut storage, last_trashed.take()) {
+                            match set_trashed(storage, &mut controller, &sidebar, &uuid, false) {
+                                Ok(_) => request_sync(storage, &worker, false),
+                                Err(err) => g_warning!(APP_DOMAIN, "Could not restore note: {}", err),
+                            }
+                        }
+                    }
+                    AppEvent::HideUndo(generation) => {
+                        if generation == undo_generation {
+                            hide_undo(&builder);
+                            last_trashed = None;
                         }
                     }
                     AppEvent::DeleteNote => {
                         if let Some(storage) = &mut storage {
                             if let Some(uuid) = storage.current {
                                 g_info!(APP_DOMAIN, "Deleting {}", uuid);
+
+                                if let Err(err) = storage.delete(&uuid) {
+                                    g_warning!(APP_DOMAIN, "Could not delete {}: {}", uuid, err);
+                                    show_notification(&builder, &format!("Could not delete note: {}.", err));
+                                    return glib::Continue(true);
+                                }
+
                                 controller.delete(&uuid);
-                                storage.delete(&uuid).unwrap();
+                                show_tags(&sidebar, &controller, storage);
+                                request_sync(storage, &worker, false);
+                            }
+                        }
+                    }
+                    AppEvent::ShowRevisions => {
+                        if let Some(storage) = &mut storage {
+                            if let Some(uuid) = storage.current {
+                                let revisions = match storage.revisions(&uuid) {
+                                    Ok(revisions) => revisions,
+                                    Err(err) => {
+                                        g_warning!(APP_DOMAIN, "Could not read revisions: {}", err);
+                                        return glib::Continue(true);
+                                    }
+                                };
+
+                                let text = storage.get_text().unwrap();
+
+                                if let Some(index) = choose_revision(&window, &revisions, &text) {
+                                    match storage.restore_revision(&revisions[index]) {
+                                        Ok(_) => {
+                                            let title = storage.get_title().unwrap();
+                                            let text = storage.get_text().unwrap();
+                                            show_note_contents(&title_entry, &text_buffer, title_entry_handler, text_buffer_handler, &title, &text);
+                                            controller.update(&storage.items[&uuid]);
+                                            request_sync(storage, &worker, false);
+                                        }
+                                        Err(err) => g_warning!(APP_DOMAIN, "Could not restore revision: {}", err),
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    AppEvent::EmptyTrash => {
+                        if let Some(storage) = &mut storage {
+                            match storage.empty_trash() {
+                                Ok(uuids) => {
+                                    g_info!(APP_DOMAIN, "Deleted {} notes from the trash", uuids.len());
+
+                                    for uuid in &uuids {
+                                        controller.delete(uuid);
+                                    }
+
+                                    show_tags(&sidebar, &controller, storage);
+                                    request_sync(storage, &worker, false);
+                                }
+                                Err(err) => g_warning!(APP_DOMAIN, "Could not empty the trash: {}", err),
                             }
                         }
                     }
@@ -597,7 +1174,18 @@ impl Application {
 
                         if let Some(uuid) = controller.select(&row) {
                             if let Some(storage) = &mut storage {
-                                storage.set_current_uuid(&uuid).unwrap();
+                                // A sync moved the note being edited to this row, its contents
+                                // are shown already.
+                                if storage.current == Some(uuid) {
+                                    return glib::Continue(true);
+                                }
+
+                                // The row may refer to a note that has just been moved or
+                                // removed by a sync.
+                                if let Err(err) = storage.set_current_uuid(&uuid) {
+                                    g_warning!(APP_DOMAIN, "Could not select note: {}", err);
+                                    return glib::Continue(true);
+                                }
 
                                 // We first disconnect the change handlers before setting the text
                                 // and content to avoid updating the storage and controller which would
@@ -613,9 +1201,18 @@ impl Application {
 
                                 let title = storage.get_title().unwrap();
                                 let text = storage.get_text().unwrap();
+                                show_flags(&app, &title_entry, &text_view, &storage.get_flags().unwrap());
+
+                                let tags = storage
+                                    .tags_of(&uuid)
+                                    .iter()
+                                    .map(|tag| tag.title.as_str())
+                                    .collect::<Vec<_>>()
+                                    .join(", ");
 
                                 title_entry.set_text(&title);
                                 text_buffer.set_text(&text);
+                                tag_entry.set_text(&tags);
 
                                 title_entry_handler = Some(title_entry.connect_changed(
                                     clone!(@strong sender => move |entry| {
@@ -651,13 +1248,7 @@ impl Application {
                             }
 
                             if !flush_timer_running {
-                                glib::source::timeout_add_seconds(5,
-                                    clone!(@strong sender => move || {
-                                        sender.send(AppEvent::FlushDirty).unwrap();
-                                        glib::Continue(false)
-                                    })
-                                );
-
+                                schedule_flush(&sender, Duration::from_secs(5));
                                 flush_timer_running = true;
                             }
                         }
@@ -665,13 +1256,265 @@ impl Application {
                     AppEvent::UpdateFilter(term) => {
                         controller.filter_rows(term);
                     }
+                    AppEvent::UpdateTags(text) => {
+                        if let Some(storage) = &mut storage {
+                            match update_tags(storage, &parse_tags(&text)) {
+                                Ok(_) => request_sync(storage, &worker, false),
+                                Err(err) => g_warning!(APP_DOMAIN, "Could not update tags: {}", err),
+                            }
+
+                            show_tags(&sidebar, &controller, storage);
+                        }
+                    }
+                    AppEvent::SelectView(view) => {
+                        if let Some(storage) = &storage {
+                            show_view(view, &controller, storage);
+                        }
+                    }
+                    AppEvent::SetFlag(flag, value) => {
+                        if let Some(storage) = &mut storage {
+                            if let (Some(uuid), Ok(mut flags)) = (storage.current, storage.get_flags()) {
+                                *flag.get_mut(&mut flags) = value;
+                                storage.set_flags(flags).unwrap();
+                                show_flags(&app, &title_entry, &text_view, &flags);
+                                controller.update(&storage.items[&uuid]);
+                                show_tags(&sidebar, &controller, storage);
+
+                                if !flush_timer_running {
+                                    schedule_flush(&sender, Duration::from_secs(5));
+                                    flush_timer_running = true;
+                                }
+                            }
+                        }
+                    }
                     AppEvent::FlushDirty => {
                         if let Some(storage) = &mut storage {
-                            if let Err(err) = storage.flush_dirty() {
-                                g_error!(APP_DOMAIN, "Could not flush: {}", err);
+                            match storage.flush_dirty() {
+                                Ok(_) => {
+                                    flush_timer_running = false;
+                                    request_sync(storage, &worker, false);
+
+                                    // Changes that could not be uploaded are retried later.
+                                    if let Some(delay) = storage.retry_in() {
+                                        schedule_flush(&sender, delay);
+                                        flush_timer_running = true;
+                                    }
+                                }
+                                Err(err) => {
+                                    g_error!(APP_DOMAIN, "Could not flush: {}", err);
+                                }
                             }
-                            else {
-                                flush_timer_running = false;
+                        }
+                    }
+                    AppEvent::Remote(identifier, response) => {
+                        // Ignore late responses of workers we have replaced.
+                        let current_worker = match &worker {
+                            Some(worker) if worker.identifier() == identifier => worker,
+                            _ => return glib::Continue(true),
+                        };
+
+                        match response {
+                            Response::Registered(Ok((credentials, session))) | Response::SignedIn(Ok((credentials, session))) => {
+                                // We have to use the credentials of the remote because encryption
+                                // parameters such as nonce and number of iterations might have
+                                // changed. Keep the current storage otherwise.
+                                if storage.as_ref().map_or(true, |s| *s.credentials() != credentials) {
+                                    match Storage::open(&credentials) {
+                                        Ok(new_storage) => {
+                                            controller.clear();
+
+                                            for item in new_storage.items.values() {
+                                                controller.insert(item);
+                                            }
+
+                                            show_tags(&sidebar, &controller, &new_storage);
+                                            storage = Some(new_storage);
+                                        }
+                                        Err(err) => {
+                                            show_notification(&builder, &format!("Could not open storage: {}.", err));
+                                            return glib::Continue(true);
+                                        }
+                                    }
+                                }
+
+                                let server = current_worker.host().to_string();
+                                let s = storage.as_mut().unwrap();
+
+                                if let Err(err) = s.connect() {
+                                    show_notification(&builder, &format!("Could not sync: {}.", err));
+                                }
+
+                                // Store the encryption password and session in the keyring.
+                                let stored = secret::store(&credentials, Some(&server)).and_then(|_| {
+                                    secret::store_session(&credentials.identifier, &session, &server)
+                                });
+
+                                if let Err(err) = stored {
+                                    show_notification(&builder, &format!("{}", err));
+                                }
+                                else {
+                                    config.add(&credentials, Some(server));
+                                    show_main_content(&builder);
+                                }
+
+                                initial_sync = true;
+                                request_sync(s, &worker, true);
+                            }
+                            Response::Registered(Err(err)) => {
+                                show_notification(&builder, &format!("Registration failed: {}.", err));
+                                worker = None;
+                            }
+                            Response::SignedIn(Err(err)) => {
+                                match err.downcast_ref::<RemoteError>() {
+                                    Some(RemoteError::MfaRequired(challenge)) => {
+                                        // Try again with the code, the worker has given up already.
+                                        if let Some(code) = ask_mfa_code(&window, &challenge.message) {
+                                            let server = current_worker.host().to_string();
+                                            let credentials = current_worker.credentials().clone();
+                                            let mfa = MfaCode { key: challenge.mfa_key.clone(), code };
+                                            sender.send(AppEvent::SignIn(server, credentials, Some(mfa))).unwrap();
+                                        }
+                                    }
+                                    _ => show_notification(&builder, &format!("Login failed: {}.", err)),
+                                }
+
+                                worker = None;
+                            }
+                            Response::SessionChanged(session) => {
+                                if let Err(err) = secret::store_session(&identifier, &session, current_worker.host()) {
+                                    g_warning!(APP_DOMAIN, "Could not store session: {}", err);
+                                }
+                            }
+                            Response::Progress(count) => {
+                                if initial_sync {
+                                    show_sync_progress(&builder, count);
+                                }
+                            }
+                            Response::Synced(result) => {
+                                if let Some(storage) = &mut storage {
+                                    let previous = storage.current;
+
+                                    let applied = match result {
+                                        Ok(result) => storage.apply_sync(result),
+                                        Err(err) => {
+                                            match err.downcast_ref::<RemoteError>() {
+                                                Some(RemoteError::ExpiredToken) => {
+                                                    // The stored session has been revoked or has
+                                                    // expired, sign in with the password instead.
+                                                    g_info!(APP_DOMAIN, "Session of {} expired, signing in again", identifier);
+
+                                                    if let Err(err) = secret::clear_session(&identifier) {
+                                                        g_warning!(APP_DOMAIN, "Could not remove session: {}", err);
+                                                    }
+
+                                                    storage.sync_failed(None);
+                                                    storage.reset_backoff();
+
+                                                    let server = current_worker.host().to_string();
+                                                    let credentials = current_worker.credentials().clone();
+                                                    sender.send(AppEvent::SignIn(server, credentials, None)).unwrap();
+                                                    return glib::Continue(true);
+                                                }
+                                                Some(RemoteError::RateLimited { retry_after }) => storage.sync_failed(*retry_after),
+                                                _ => storage.sync_failed(None),
+                                            }
+
+                                            Err(err)
+                                        }
+                                    };
+
+                                    match applied {
+                                        Ok(uuids) => {
+                                            if initial_sync {
+                                                get_widget!(builder, gtk::Revealer, "notification-revealer").set_reveal_child(false);
+                                            }
+
+                                            for uuid in &uuids {
+                                                match storage.items.get(uuid) {
+                                                    Some(item) => controller.update(item),
+                                                    None => controller.delete(uuid),
+                                                }
+                                            }
+
+                                            show_tags(&sidebar, &controller, storage);
+
+                                            match (previous, storage.current) {
+                                                // A sync conflict kept the note being edited as
+                                                // conflicted copy, so the editor already shows it.
+                                                (Some(previous), Some(current)) if previous != current && storage.items.contains_key(&previous) => {
+                                                    controller.select_uuid(&current);
+                                                    let title = storage.get_title().unwrap_or_default();
+                                                    show_notification(&builder, &format!("This note has been changed elsewhere, your version has been kept as “{}”.", title));
+                                                }
+                                                // A uuid conflict moved the current note.
+                                                (Some(previous), Some(current)) if previous != current => {
+                                                    controller.select_uuid(&current);
+                                                    let title = storage.get_title().unwrap();
+                                                    let text = storage.get_text().unwrap();
+                                                    show_flags(&app, &title_entry, &text_view, &storage.get_flags().unwrap());
+                                                    show_note_contents(&title_entry, &text_buffer, title_entry_handler, text_buffer_handler, &title, &text);
+                                                }
+                                                // The current note has no local changes if it was
+                                                // retrieved, so show the remote version.
+                                                (_, Some(current)) if uuids.contains(&current) => {
+                                                    let title = storage.get_title().unwrap();
+                                                    let text = storage.get_text().unwrap();
+                                                    show_flags(&app, &title_entry, &text_view, &storage.get_flags().unwrap());
+                                                    show_note_contents(&title_entry, &text_buffer, title_entry_handler, text_buffer_handler, &title, &text);
+                                                }
+                                                _ => {}
+                                            }
+                                        }
+                                        Err(err) => {
+                                            g_warning!(APP_DOMAIN, "Could not sync: {}", err);
+
+                                            if initial_sync {
+                                                show_notification(&builder, &format!("Could not sync: {}.", err));
+                                            }
+                                        }
+                                    }
+
+                                    initial_sync = false;
+
+                                    if !flush_timer_running {
+                                        if let Some(delay) = storage.retry_in() {
+                                            schedule_flush(&sender, delay);
+                                            flush_timer_running = true;
+                                        }
+                                    }
+                                }
+                            }
+                            Response::PasswordChanged(result) => {
+                                if let Some(storage) = &mut storage {
+                                    match result {
+                                        Ok(credentials) => {
+                                            // Signing in again must use the new password.
+                                            if let Some(worker) = &mut worker {
+                                                worker.set_credentials(credentials.clone());
+                                            }
+
+                                            // The remote accepted the new password, so there is
+                                            // no going back even if applying it locally fails.
+                                            match commit_password_change(storage, &mut config, &credentials) {
+                                                Ok(_) => {
+                                                    show_notification(&builder, "Password changed.");
+                                                    request_sync(storage, &worker, true);
+                                                }
+                                                Err(err) => {
+                                                    g_warning!(APP_DOMAIN, "Could not apply password change: {}", err);
+                                                    show_notification(&builder, &format!("Password changed but not applied yet: {}. Please restart.", err));
+                                                }
+                                            }
+                                        }
+                                        Err(err) => {
+                                            if let Err(err) = abort_password_change(storage) {
+                                                g_warning!(APP_DOMAIN, "Could not roll back password change: {}", err);
+                                            }
+
+                                            show_notification(&builder, &format!("Could not change password: {}.", err));
+                                        }
+                                    }
+                                }
                             }
                         }
                     }
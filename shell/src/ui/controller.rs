@@ -1,18 +1,49 @@
 use chrono::{DateTime, Utc};
 use gio::prelude::*;
 use gtk::prelude::*;
-use standardfile::{Item as StandardItem, Note};
-use std::{cell::RefCell, cmp, cmp::{Ord, Ordering}, collections::HashMap, rc::Rc};
+use crate::ui::sidebar::View;
+use standardfile::{Item as StandardItem, Note, NoteFlags};
+use std::{cell::RefCell, cmp, cmp::{Ord, Ordering}, collections::{HashMap, HashSet}, rc::Rc};
 use uuid::Uuid;
 
 struct Item {
     uuid: Uuid,
     label: gtk::Label,
+    pin: gtk::Image,
     last_updated: DateTime<Utc>,
+    flags: NoteFlags,
+}
+
+/// Criteria a row must meet to be shown.
+struct Filter {
+    term: Option<String>,
+    view: View,
+    /// Notes of the selected tag.
+    notes: HashSet<Uuid>,
+}
+
+impl Filter {
+    fn matches(&self, item: &Item) -> bool {
+        let term_matches = self.term.as_ref().map_or(true, |term| {
+            item.label.get_text().to_string().to_lowercase().contains(term)
+        });
+
+        let flags = &item.flags;
+
+        let view_matches = match self.view {
+            View::All => !flags.archived && !flags.trashed,
+            View::Tag(_) => !flags.archived && !flags.trashed && self.notes.contains(&item.uuid),
+            View::Archived => flags.archived && !flags.trashed,
+            View::Trash => flags.trashed,
+        };
+
+        term_matches && view_matches
+    }
 }
 
 pub struct Controller {
     items: Rc<RefCell<HashMap<gtk::ListBoxRow, Item>>>,
+    filter: Rc<RefCell<Filter>>,
     list_box: gtk::ListBox,
     title_entry: gtk::Entry,
     note_stack: gtk::Stack,
@@ -21,9 +52,10 @@ pub struct Controller {
     binding: Option<glib::Binding>,
 }
 
+/// Pinned notes come first, the rest by date.
 impl Ord for Item {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.last_updated.cmp(&other.last_updated)
+        (self.flags.pinned, self.last_updated).cmp(&(other.flags.pinned, other.last_updated))
     }
 }
 
@@ -35,7 +67,7 @@ impl PartialOrd for Item {
 
 impl PartialEq for Item {
     fn eq(&self, other: &Self) -> bool {
-        self.last_updated == other.last_updated
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -45,6 +77,7 @@ impl Controller {
     pub fn new(builder: &gtk::Builder) -> Self {
         let controller = Self {
             items: Rc::new(RefCell::new(HashMap::new())),
+            filter: Rc::new(RefCell::new(Filter { term: None, view: View::All, notes: HashSet::new() })),
             list_box: get_widget!(builder, gtk::ListBox, "note-list"),
             title_entry: get_widget!(builder, gtk::Entry, "title-entry"),
             note_stack: get_widget!(builder, gtk::Stack, "right-hand-stack"),
@@ -62,10 +95,16 @@ impl Controller {
             })
         )));
 
+        controller.list_box.set_filter_func(Some(Box::new(
+            clone!(@strong controller.items as items, @strong controller.filter as filter => move |row| {
+                items.borrow().get(row).map_or(true, |item| filter.borrow().matches(item))
+            })
+        )));
+
         controller
     }
 
-    fn insert_note(&mut self, note: &Note) {
+    fn insert_note(&mut self, note: &Note, select: bool) {
         let label = gtk::Label::new(None);
         label.set_halign(gtk::Align::Start);
         label.set_margin_start(9);
@@ -75,8 +114,17 @@ impl Controller {
         label.set_widget_name("iridium-note-row-label");
         label.set_text(&note.title);
 
+        let pin = gtk::Image::from_icon_name(Some("view-pin-symbolic"), gtk::IconSize::Menu);
+        pin.set_margin_end(9);
+        pin.set_no_show_all(true);
+        pin.set_visible(note.flags.pinned);
+
+        let row_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        row_box.pack_start(&label, true, true, 0);
+        row_box.pack_end(&pin, false, false, 0);
+
         let row = gtk::ListBoxRow::new();
-        row.add(&label);
+        row.add(&row_box);
         row.set_widget_name("iridium-note-row");
         row.show_all();
 
@@ -86,7 +134,9 @@ impl Controller {
             items.insert(row.clone(), Item {
                 uuid: note.uuid,
                 label: label.clone(),
+                pin,
                 last_updated: note.updated_at,
+                flags: note.flags,
             });
 
             if items.len() == 1 {
@@ -95,7 +145,10 @@ impl Controller {
         }
 
         self.list_box.insert(&row, 0);
-        self.list_box.select_row(Some(&row));
+
+        if select {
+            self.list_box.select_row(Some(&row));
+        }
     }
 
     pub fn insert(&mut self, item: &StandardItem) {
@@ -104,26 +157,55 @@ impl Controller {
         }
 
         if let StandardItem::Note(note) = item {
-            self.insert_note(&note);
+            self.insert_note(note, true);
+        }
+    }
+
+    /// Show an item that has been added or changed elsewhere without changing the selection.
+    pub fn update(&mut self, item: &StandardItem) {
+        if let StandardItem::Note(note) = item {
+            if !self.have(&note.uuid) {
+                self.insert_note(note, false);
+                return;
+            }
+
+            let mut rows = Vec::new();
+
+            for (row, item) in self.items.borrow_mut().iter_mut().filter(|(_, item)| item.uuid == note.uuid) {
+                item.label.set_text(&note.title);
+                item.pin.set_visible(note.flags.pinned);
+                item.last_updated = note.updated_at;
+                item.flags = note.flags;
+                rows.push(row.clone());
+            }
+
+            // Sorting and filtering look at the items, so they must not be borrowed anymore.
+            for row in rows {
+                row.changed();
+            }
         }
     }
 
     pub fn delete(&mut self, uuid: &Uuid) {
         let mut index = 0;
+        let mut was_selected = false;
         let mut items = self.items.borrow_mut();
 
         for (row, _) in items.iter().filter(|&(_, item)| item.uuid == *uuid) {
             index = cmp::max(0, row.get_index() - 1);
+            was_selected |= row.is_selected();
             self.list_box.remove(row);
         }
 
         items.retain(|_, item| item.uuid != *uuid);
 
-        if items.len() > 0 {
+        // Keep the selection if some other row was selected, e.g. when the note has been deleted
+        // on another device.
+        if !items.is_empty() && was_selected {
             let new_selected_row = self.list_box.get_row_at_index(index).unwrap();
             self.list_box.select_row(Some(&new_selected_row));
         }
-        else {
+        else if items.is_empty() {
             self.note_stack.set_visible_child(&self.note_info);
         }
     }
@@ -143,11 +225,21 @@ impl Controller {
         self.note_stack.set_visible_child(&self.note_info);
     }
 
+    /// Select the first note that is shown.
     pub fn select_first(&self) {
         let items = self.items.borrow();
-        let most_recent = items.iter().max_by(|(_, x), (_, y)| x.cmp(y));
+        let filter = self.filter.borrow();
+        let most_recent = items.iter().filter(|(_, item)| filter.matches(item)).max_by(|(_, x), (_, y)| x.cmp(y));
+
+        match most_recent {
+            Some((row, _)) => self.list_box.select_row(Some(row)),
+            None => self.note_stack.set_visible_child(&self.note_info),
+        }
+    }
 
-        if let Some((row, _)) = most_recent {
+    /// Select the row of the note `uuid`, e.g. after a sync moved the current note.
+    pub fn select_uuid(&self, uuid: &Uuid) {
+        if let Some((row, _)) = self.items.borrow().iter().find(|(_, item)| item.uuid == *uuid) {
             self.list_box.select_row(Some(row));
         }
     }
@@ -158,6 +250,7 @@ impl Controller {
         }
 
         if let Some(item) = self.items.borrow().get(selected_row) {
+            self.note_stack.set_visible_child(&self.note_content);
             self.binding = Some(self.title_entry.bind_property("text", &item.label, "label").build().unwrap());
             return Some(item.uuid);
         }
@@ -183,18 +276,18 @@ impl Controller {
     }
 
     pub fn filter_rows(&self, term: Option<String>) {
-        if let Some(term) = term {
-            self.list_box.set_filter_func(Some(Box::new(
-                clone!(@strong self.items as items => move |row| {
-                    let items = items.borrow();
-                    let label_text = items[row].label.get_text().to_string().to_lowercase();
-                    label_text.contains(&term)
-                })
-            )));
-        }
-        else {
-            self.list_box.set_filter_func(None);
-        }
+        self.filter.borrow_mut().term = term;
+        self.list_box.invalidate_filter();
+    }
+
+    /// Only show the notes of `view`. `notes` are the notes of the tag if a tag is shown.
+    pub fn filter_view(&self, view: View, notes: HashSet<Uuid>) {
+        let mut filter = self.filter.borrow_mut();
+        filter.view = view;
+        filter.notes = notes;
+        drop(filter);
+
+        self.list_box.invalidate_filter();
     }
 
     fn have(&self, uuid: &Uuid) -> bool {
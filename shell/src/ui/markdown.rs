@@ -0,0 +1,346 @@
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+/// Name of the tag indenting list items nested `depth` levels deep.
+pub fn indent_tag_name(depth: usize) -> String {
+    format!("indent-{}", depth)
+}
+
+/// Text formatted with a tag, as character offsets into the rendered text.
+#[derive(Debug, PartialEq)]
+pub struct Span {
+    pub start: i32,
+    pub end: i32,
+    pub tag: String,
+}
+
+/// Rendered text of a link, as character offsets into the rendered text.
+#[derive(Debug, PartialEq)]
+pub struct Link {
+    pub start: i32,
+    pub end: i32,
+    pub url: String,
+}
+
+/// Start of a block, as line in the source and character offset into the rendered text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Anchor {
+    pub line: i32,
+    pub offset: i32,
+}
+
+/// Markdown rendered to plain text, with the tags to format it and the positions needed to
+/// follow links and scroll along with the source.
+#[derive(Default)]
+pub struct Rendered {
+    pub text: String,
+    pub spans: Vec<Span>,
+    pub links: Vec<Link>,
+    pub anchors: Vec<Anchor>,
+}
+
+impl Rendered {
+    /// Target of the link at character `offset`, if there is one.
+    pub fn link_at(&self, offset: i32) -> Option<&str> {
+        self.links
+            .iter()
+            .find(|link| link.start <= offset && offset < link.end)
+            .map(|link| link.url.as_str())
+    }
+
+    /// Position to scroll the rendered text to when source `line` is shown at the top. Within a
+    /// block, the position is interpolated by source line between the start of the block and the
+    /// start of the next one or `end` after the last block. `y_at` returns the position of a
+    /// character offset and `line_count` is the number of lines of the source.
+    pub fn scroll_position<F: Fn(i32) -> f64>(&self, line: i32, line_count: i32, end: f64, y_at: F) -> f64 {
+        let next = self.anchors.partition_point(|anchor| anchor.line <= line);
+
+        if next == 0 {
+            return 0.0;
+        }
+
+        let current = self.anchors[next - 1];
+
+        let (end_line, end_y) = match self.anchors.get(next) {
+            Some(anchor) => (anchor.line, y_at(anchor.offset)),
+            None => (line_count, end),
+        };
+
+        let start_y = y_at(current.offset);
+        let fraction = (line - current.line) as f64 / (end_line - current.line).max(1) as f64;
+        start_y + fraction * (end_y - start_y)
+    }
+}
+
+/// Turns the events of the Markdown parser into text and the names of the tags formatting it.
+struct Renderer {
+    /// Byte offsets of the line breaks in the source.
+    newlines: Vec<usize>,
+    /// Number of characters written so far.
+    count: i32,
+    /// Names of the tags applied to written text.
+    tags: Vec<String>,
+    /// Next number of each open list, `None` for bullet lists.
+    lists: Vec<Option<u64>>,
+    /// Bullet of the current list item, written with its first text unless a task list marker
+    /// replaces it.
+    bullet: Option<String>,
+    /// Start and target of the current link.
+    link: Option<(i32, String)>,
+    rendered: Rendered,
+}
+
+impl Renderer {
+    fn new(markdown: &str) -> Self {
+        Self {
+            newlines: markdown.match_indices('\n').map(|(index, _)| index).collect(),
+            count: 0,
+            tags: Vec::new(),
+            lists: Vec::new(),
+            bullet: None,
+            link: None,
+            rendered: Rendered::default(),
+        }
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.rendered.text.push_str(text);
+        self.count += text.chars().count() as i32;
+    }
+
+    fn write(&mut self, text: &str) {
+        let start = self.count;
+        self.insert(text);
+
+        if start == self.count {
+            return;
+        }
+
+        for tag in &self.tags {
+            self.rendered.spans.push(Span { start, end: self.count, tag: tag.clone() });
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(bullet) = self.bullet.take() {
+            self.write(&bullet);
+        }
+
+        self.write(text);
+    }
+
+    /// Start a block that begins at byte `position` of the source. Blocks are separated by an
+    /// empty line, except within lists and at the start of a list item.
+    fn block(&mut self, position: usize) {
+        if self.count > 0 && self.bullet.is_none() {
+            let wanted = if self.lists.is_empty() { 2 } else { 1 };
+            let present = self.rendered.text.chars().rev().take_while(|c| *c == '\n').count();
+
+            if present < wanted {
+                self.insert(&"\n".repeat(wanted - present));
+            }
+        }
+
+        let line = self.newlines.partition_point(|newline| *newline < position) as i32;
+        self.rendered.anchors.push(Anchor { line, offset: self.count });
+    }
+
+    fn start(&mut self, tag: Tag, position: usize) {
+        match tag {
+            Tag::Paragraph => self.block(position),
+            Tag::Heading { level, .. } => {
+                self.block(position);
+                self.tags.push(format!("h{}", level as usize));
+            }
+            Tag::BlockQuote(_) => {
+                self.block(position);
+                self.tags.push("quote".to_string());
+            }
+            Tag::CodeBlock(_) => {
+                self.block(position);
+                self.tags.push("code-block".to_string());
+            }
+            Tag::List(start) => {
+                self.block(position);
+                self.lists.push(start);
+                self.tags.push(indent_tag_name(self.lists.len()));
+            }
+            Tag::Item => {
+                self.block(position);
+
+                self.bullet = Some(match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                });
+            }
+            Tag::Emphasis => self.tags.push("emphasis".to_string()),
+            Tag::Strong => self.tags.push("strong".to_string()),
+            Tag::Strikethrough => self.tags.push("strikethrough".to_string()),
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                self.text("");
+                self.tags.push("link".to_string());
+                self.link = Some((self.count, dest_url.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(_) | TagEnd::BlockQuote(_) | TagEnd::CodeBlock | TagEnd::Emphasis
+            | TagEnd::Strong | TagEnd::Strikethrough => {
+                self.tags.pop();
+            }
+            TagEnd::List(_) => {
+                self.tags.pop();
+                self.lists.pop();
+            }
+            TagEnd::Item => {
+                // Empty items still show their bullet.
+                self.text("");
+            }
+            TagEnd::Link | TagEnd::Image => {
+                self.tags.pop();
+
+                if let Some((start, url)) = self.link.take() {
+                    self.rendered.links.push(Link { start, end: self.count, url });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn render(mut self, markdown: &str) -> Rendered {
+        let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+
+        for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+            match event {
+                Event::Start(tag) => self.start(tag, range.start),
+                Event::End(tag) => self.end(tag),
+                Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => self.text(&text),
+                Event::Code(text) => {
+                    self.tags.push("code".to_string());
+                    self.text(&text);
+                    self.tags.pop();
+                }
+                Event::SoftBreak => self.text(" "),
+                Event::HardBreak => self.text("\n"),
+                Event::Rule => {
+                    self.block(range.start);
+                    self.tags.push("rule".to_string());
+                    self.text(&"\u{2015}".repeat(24));
+                    self.tags.pop();
+                }
+                Event::TaskListMarker(checked) => {
+                    self.bullet = Some(if checked { "☑ " } else { "☐ " }.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        self.rendered
+    }
+}
+
+/// Render `markdown` to text formatted with the tags created by the preview.
+pub fn render(markdown: &str) -> Rendered {
+    Renderer::new(markdown).render(markdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rendered text covered by spans with `tag`.
+    fn tagged(rendered: &Rendered, tag: &str) -> Vec<String> {
+        rendered.spans
+            .iter()
+            .filter(|span| span.tag == tag)
+            .map(|span| rendered.text.chars().skip(span.start as usize).take((span.end - span.start) as usize).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_blocks() {
+        let rendered = render("# Title\nSome *words*\n\n```\ncode\n```");
+        assert_eq!(rendered.text, "Title\n\nSome words\n\ncode\n");
+        assert_eq!(tagged(&rendered, "h1"), vec!["Title"]);
+        assert_eq!(tagged(&rendered, "emphasis"), vec!["words"]);
+        assert_eq!(tagged(&rendered, "code-block"), vec!["code\n"]);
+    }
+
+    #[test]
+    fn test_lists() {
+        let rendered = render("3. first\n4. second\n   - nested\n\nafter");
+        assert_eq!(rendered.text, "3. first\n4. second\n• nested\n\nafter");
+        assert_eq!(tagged(&rendered, "indent-1"), vec!["3. ", "first", "4. ", "second", "• ", "nested"]);
+        assert_eq!(tagged(&rendered, "indent-2"), vec!["• ", "nested"]);
+
+        // Empty items keep their bullet.
+        assert_eq!(render("-\n- item").text, "• \n• item");
+    }
+
+    #[test]
+    fn test_task_markers() {
+        let rendered = render("- [ ] open\n- [x] done\n- plain");
+        assert_eq!(rendered.text, "☐ open\n☑ done\n• plain");
+    }
+
+    #[test]
+    fn test_links() {
+        let rendered = render("See [the docs](https://example.com/ä) and ![](image.png).");
+        assert_eq!(rendered.text, "See the docs and .");
+        assert_eq!(rendered.links, vec![
+            Link { start: 4, end: 12, url: "https://example.com/ä".to_string() },
+            Link { start: 17, end: 17, url: "image.png".to_string() },
+        ]);
+        assert_eq!(tagged(&rendered, "link"), vec!["the docs"]);
+
+        assert_eq!(rendered.link_at(3), None);
+        assert_eq!(rendered.link_at(4), Some("https://example.com/ä"));
+        assert_eq!(rendered.link_at(11), Some("https://example.com/ä"));
+        assert_eq!(rendered.link_at(12), None);
+        assert_eq!(rendered.link_at(17), None);
+    }
+
+    #[test]
+    fn test_anchors() {
+        // Offsets count characters, not bytes.
+        let rendered = render("ä\n\n\n# Head\n\n- one\n- two\n\n---");
+        assert_eq!(rendered.anchors, vec![
+            Anchor { line: 0, offset: 0 },
+            Anchor { line: 3, offset: 3 },
+            Anchor { line: 5, offset: 9 },
+            Anchor { line: 5, offset: 9 },
+            Anchor { line: 6, offset: 15 },
+            Anchor { line: 8, offset: 22 },
+        ]);
+    }
+
+    #[test]
+    fn test_scroll_position() {
+        let rendered = Rendered {
+            anchors: vec![Anchor { line: 2, offset: 10 }, Anchor { line: 6, offset: 30 }],
+            ..Default::default()
+        };
+
+        let y_at = |offset| offset as f64 * 10.0;
+
+        // Before the first block the top is shown.
+        assert_eq!(rendered.scroll_position(0, 10, 1000.0, y_at), 0.0);
+        assert_eq!(rendered.scroll_position(1, 10, 1000.0, y_at), 0.0);
+
+        // Within a block, the position is interpolated towards the start of the next block.
+        assert_eq!(rendered.scroll_position(2, 10, 1000.0, y_at), 100.0);
+        assert_eq!(rendered.scroll_position(3, 10, 1000.0, y_at), 150.0);
+        assert_eq!(rendered.scroll_position(5, 10, 1000.0, y_at), 250.0);
+
+        // The last block extends to the end of the text.
+        assert_eq!(rendered.scroll_position(6, 10, 1000.0, y_at), 300.0);
+        assert_eq!(rendered.scroll_position(8, 10, 1000.0, y_at), 650.0);
+
+        assert_eq!(Rendered::default().scroll_position(3, 10, 1000.0, y_at), 0.0);
+    }
+}
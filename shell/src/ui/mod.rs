@@ -2,3 +2,7 @@
 pub mod utils;
 pub mod application;
 pub mod controller;
+pub mod markdown;
+pub mod preview;
+pub mod revisions;
+pub mod sidebar;
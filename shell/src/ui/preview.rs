@@ -0,0 +1,206 @@
+use crate::consts::APP_DOMAIN;
+use crate::ui::markdown::{self, Rendered};
+use glib::translate::ToGlib;
+use gtk::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Left margin per level of nested lists.
+const INDENT: i32 = 24;
+
+/// Tag indenting list items nested `depth` levels deep, created on first use.
+fn indent_tag(buffer: &gtk::TextBuffer, depth: usize) {
+    let name = markdown::indent_tag_name(depth);
+
+    if let Some(table) = buffer.get_tag_table() {
+        if table.lookup(&name).is_none() {
+            let tag = gtk::TextTag::new(Some(&name));
+            tag.set_property_left_margin(INDENT * depth as i32);
+            table.add(&tag);
+        }
+    }
+}
+
+/// Fill `buffer` with the rendered text and apply its tags.
+fn apply(buffer: &gtk::TextBuffer, rendered: &Rendered) {
+    buffer.set_text(&rendered.text);
+
+    for span in &rendered.spans {
+        if let Some(depth) = span.tag.strip_prefix("indent-").and_then(|depth| depth.parse().ok()) {
+            indent_tag(buffer, depth);
+        }
+
+        buffer.apply_tag_by_name(&span.tag, &buffer.get_iter_at_offset(span.start), &buffer.get_iter_at_offset(span.end));
+    }
+}
+
+fn add_tags(buffer: &gtk::TextBuffer) {
+    let table = match buffer.get_tag_table() {
+        Some(table) => table,
+        None => return,
+    };
+
+    for (level, scale) in [2.0, 1.6, 1.35, 1.2, 1.1, 1.0].iter().enumerate() {
+        let tag = gtk::TextTag::new(Some(&format!("h{}", level + 1)));
+        tag.set_property_weight(pango::Weight::Bold.to_glib());
+        tag.set_property_scale(*scale);
+        tag.set_property_pixels_below_lines(6);
+        table.add(&tag);
+    }
+
+    let tag = gtk::TextTag::new(Some("emphasis"));
+    tag.set_property_style(pango::Style::Italic);
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("strong"));
+    tag.set_property_weight(pango::Weight::Bold.to_glib());
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("strikethrough"));
+    tag.set_property_strikethrough(true);
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("code"));
+    tag.set_property_family(Some("monospace"));
+    tag.set_property_background(Some("#eeeeee"));
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("code-block"));
+    tag.set_property_family(Some("monospace"));
+    tag.set_property_paragraph_background(Some("#f4f4f4"));
+    tag.set_property_left_margin(12);
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("quote"));
+    tag.set_property_foreground(Some("#666666"));
+    tag.set_property_style(pango::Style::Italic);
+    tag.set_property_left_margin(INDENT);
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("link"));
+    tag.set_property_foreground(Some("#1a5fb4"));
+    tag.set_property_underline(pango::Underline::Single);
+    table.add(&tag);
+
+    let tag = gtk::TextTag::new(Some("rule"));
+    tag.set_property_foreground(Some("#aaaaaa"));
+    table.add(&tag);
+}
+
+/// Rendered Markdown of the note next to the text view, scrolled along with it.
+pub struct Preview {
+    source: gtk::TextView,
+    view: gtk::TextView,
+    window: gtk::ScrolledWindow,
+    rendered: RefCell<Rendered>,
+}
+
+impl Preview {
+    pub fn new(builder: &gtk::Builder) -> Rc<Self> {
+        let preview = Rc::new(Self {
+            source: get_widget!(builder, gtk::TextView, "text-view"),
+            view: get_widget!(builder, gtk::TextView, "preview-view"),
+            window: get_widget!(builder, gtk::ScrolledWindow, "preview-window"),
+            rendered: RefCell::new(Rendered::default()),
+        });
+
+        add_tags(&preview.view.get_buffer().unwrap());
+
+        preview.source.get_buffer().unwrap().connect_changed(
+            clone!(@weak preview => move |_| {
+                preview.update();
+            })
+        );
+
+        if let Some(adjustment) = preview.source.get_vadjustment() {
+            adjustment.connect_value_changed(
+                clone!(@weak preview => move |_| {
+                    preview.sync_scroll();
+                })
+            );
+
+            // The range changes when the text is edited or the window resized.
+            adjustment.connect_changed(
+                clone!(@weak preview => move |_| {
+                    preview.sync_scroll();
+                })
+            );
+        }
+
+        preview.view.connect_button_release_event(
+            clone!(@weak preview => @default-return glib::signal::Inhibit(false), move |_, event| {
+                if event.get_button() == 1 {
+                    let (x, y) = event.get_position();
+                    preview.open_link(x as i32, y as i32, event.get_time());
+                }
+
+                glib::signal::Inhibit(false)
+            })
+        );
+
+        preview
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        self.window.set_visible(visible);
+        self.update();
+    }
+
+    /// Render the text of the note if the preview is shown.
+    fn update(&self) {
+        if !self.window.get_visible() {
+            return;
+        }
+
+        let source = self.source.get_buffer().unwrap();
+        let text = source.get_text(&source.get_start_iter(), &source.get_end_iter(), false).unwrap();
+        let rendered = markdown::render(text.as_str());
+
+        apply(&self.view.get_buffer().unwrap(), &rendered);
+        *self.rendered.borrow_mut() = rendered;
+        self.sync_scroll();
+    }
+
+    /// Scroll to the block shown at the top of the text view. Within a block, the position is
+    /// interpolated by source line.
+    fn sync_scroll(&self) {
+        let (source, target) = match (self.source.get_vadjustment(), self.view.get_vadjustment()) {
+            (Some(source), Some(target)) => (source, target),
+            _ => return,
+        };
+
+        if !self.window.get_visible() {
+            return;
+        }
+
+        if source.get_value() >= source.get_upper() - source.get_page_size() && source.get_value() > 0.0 {
+            target.set_value(target.get_upper() - target.get_page_size());
+            return;
+        }
+
+        let line = self.source.get_line_at_y(source.get_value() as i32).0.get_line();
+        let line_count = self.source.get_buffer().unwrap().get_line_count();
+        let buffer = self.view.get_buffer().unwrap();
+        let y_at = |offset| self.view.get_iter_location(&buffer.get_iter_at_offset(offset)).y as f64;
+
+        target.set_value(self.rendered.borrow().scroll_position(line, line_count, target.get_upper(), y_at));
+    }
+
+    /// Open the link at window coordinates `x` and `y`, if there is one.
+    fn open_link(&self, x: i32, y: i32, time: u32) {
+        let (x, y) = self.view.window_to_buffer_coords(gtk::TextWindowType::Widget, x, y);
+
+        let offset = match self.view.get_iter_at_location(x, y) {
+            Some(iter) => iter.get_offset(),
+            None => return,
+        };
+
+        if let Some(url) = self.rendered.borrow().link_at(offset) {
+            let window = self.view.get_toplevel().and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok());
+
+            if let Err(err) = gtk::show_uri_on_window(window.as_ref(), url, time) {
+                g_warning!(APP_DOMAIN, "Could not open {}: {}", url, err);
+            }
+        }
+    }
+}
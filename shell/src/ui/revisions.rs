@@ -0,0 +1,98 @@
+use crate::consts::REVISIONS_UI;
+use crate::storage::Revision;
+use gtk::prelude::*;
+use similar::{ChangeTag, TextDiff};
+
+/// Show the changes made to `current` since `revision`: removed lines are struck through,
+/// added lines highlighted.
+fn show_diff(buffer: &gtk::TextBuffer, revision: &str, current: &str) {
+    buffer.set_text("");
+
+    for change in TextDiff::from_lines(revision, current).iter_all_changes() {
+        let (prefix, tag) = match change.tag() {
+            ChangeTag::Equal => ("  ", None),
+            ChangeTag::Delete => ("- ", Some("deleted")),
+            ChangeTag::Insert => ("+ ", Some("inserted")),
+        };
+
+        let start = buffer.get_char_count();
+        let mut line = format!("{}{}", prefix, change.value());
+
+        if change.missing_newline() {
+            line.push('\n');
+        }
+
+        buffer.insert(&mut buffer.get_end_iter(), &line);
+
+        if let Some(tag) = tag {
+            buffer.apply_tag_by_name(tag, &buffer.get_iter_at_offset(start), &buffer.get_end_iter());
+        }
+    }
+}
+
+fn add_tag(buffer: &gtk::TextBuffer, name: &str, background: &str, strikethrough: bool) {
+    let tag = gtk::TextTag::new(Some(name));
+    tag.set_property_background(Some(background));
+    tag.set_property_strikethrough(strikethrough);
+
+    if let Some(table) = buffer.get_tag_table() {
+        table.add(&tag);
+    }
+}
+
+/// Let the user pick one of `revisions` to restore, comparing each with the `current` text.
+/// Returns the index of the chosen revision or `None` if the dialog was cancelled.
+pub fn choose_revision(window: &gtk::ApplicationWindow, revisions: &[Revision], current: &str) -> Option<usize> {
+    let builder = gtk::Builder::from_resource(REVISIONS_UI);
+    let dialog = get_widget!(builder, gtk::Dialog, "revisions-dialog");
+    let stack = get_widget!(builder, gtk::Stack, "revisions-stack");
+    let list_box = get_widget!(builder, gtk::ListBox, "revision-list");
+    let restore_button = get_widget!(builder, gtk::Button, "revisions-button-restore");
+    let buffer = get_widget!(builder, gtk::TextView, "revision-diff").get_buffer().unwrap();
+
+    add_tag(&buffer, "deleted", "#fbe3e4", true);
+    add_tag(&buffer, "inserted", "#e3f6e3", false);
+
+    if !revisions.is_empty() {
+        stack.set_visible_child(&get_widget!(builder, gtk::Paned, "revisions-paned"));
+    }
+
+    for revision in revisions {
+        let created_at = revision.created_at.with_timezone(&chrono::Local);
+        let label = gtk::Label::new(Some(&format!("{}\n{}", created_at.format("%Y-%m-%d %H:%M"), revision.note.title)));
+        label.set_halign(gtk::Align::Start);
+        label.set_margin_start(9);
+        label.set_margin_end(9);
+        label.set_margin_top(6);
+        label.set_margin_bottom(6);
+        label.show();
+        list_box.add(&label);
+    }
+
+    let texts = revisions.iter().map(|revision| revision.note.text.clone()).collect::<Vec<_>>();
+    let current = current.to_string();
+
+    list_box.connect_row_selected(
+        clone!(@weak restore_button, @weak buffer => move |_, row| {
+            let text = row.and_then(|row| texts.get(row.get_index() as usize));
+            restore_button.set_sensitive(text.is_some());
+
+            if let Some(text) = text {
+                show_diff(&buffer, text, &current);
+            }
+        })
+    );
+
+    list_box.select_row(list_box.get_row_at_index(0).as_ref());
+
+    dialog.set_transient_for(Some(window));
+    dialog.set_modal(true);
+
+    let selected = match dialog.run() {
+        gtk::ResponseType::Ok => list_box.get_selected_row().map(|row| row.get_index() as usize),
+        _ => None,
+    };
+
+    dialog.close();
+    selected.filter(|index| *index < revisions.len())
+}
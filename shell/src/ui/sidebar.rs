@@ -0,0 +1,206 @@
+use gtk::prelude::*;
+use standardfile::Tag;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Columns of the tag tree.
+const COLUMN_TITLE: u32 = 0;
+const COLUMN_COUNT: u32 = 1;
+const COLUMN_VIEW: u32 = 2;
+
+/// Values of the view column for rows that are not tags.
+static ALL_NOTES: &str = "";
+static ARCHIVED: &str = "archived";
+static TRASH: &str = "trash";
+
+/// Notes shown in the note list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum View {
+    /// All notes that are neither archived nor trashed.
+    All,
+    /// Notes of a tag that are neither archived nor trashed.
+    Tag(Uuid),
+    Archived,
+    Trash,
+}
+
+/// Tree of all tags with the number of notes they contain. All notes, archived and trashed notes
+/// have rows of their own.
+pub struct Sidebar {
+    view: gtk::TreeView,
+    store: gtk::TreeStore,
+    /// Tag titles offered when completing the tag entry.
+    titles: gtk::ListStore,
+}
+
+/// Part of the comma-separated tag entry text that is currently being typed.
+fn last_tag(text: &str) -> &str {
+    text.rsplit(',').next().unwrap_or_default().trim_start()
+}
+
+fn add_column(view: &gtk::TreeView, column: u32, expand: bool) {
+    let renderer = gtk::CellRendererText::new();
+    let view_column = gtk::TreeViewColumn::new();
+
+    view_column.pack_start(&renderer, true);
+    view_column.add_attribute(&renderer, "text", column as i32);
+    view_column.set_expand(expand);
+    view.append_column(&view_column);
+}
+
+impl Sidebar {
+    pub fn new(builder: &gtk::Builder, tag_entry: &gtk::Entry) -> Self {
+        let sidebar = Self {
+            view: get_widget!(builder, gtk::TreeView, "tag-tree"),
+            store: gtk::TreeStore::new(&[String::static_type(), String::static_type(), String::static_type()]),
+            titles: gtk::ListStore::new(&[String::static_type()]),
+        };
+
+        add_column(&sidebar.view, COLUMN_TITLE, true);
+        add_column(&sidebar.view, COLUMN_COUNT, false);
+        sidebar.view.set_model(Some(&sidebar.store));
+
+        let completion = gtk::EntryCompletion::new();
+        completion.set_model(Some(&sidebar.titles));
+        completion.set_text_column(0);
+
+        // Complete the tag being typed rather than the whole comma-separated list.
+        completion.set_match_func(|completion, key, iter| {
+            let prefix = last_tag(key);
+
+            !prefix.is_empty() && completion.get_model()
+                .and_then(|model| model.get_value(iter, 0).get::<String>().ok().flatten())
+                .map_or(false, |title| title.to_lowercase().starts_with(prefix))
+        });
+
+        completion.connect_match_selected(
+            clone!(@weak tag_entry => @default-return glib::signal::Inhibit(false), move |_, model, iter| {
+                if let Ok(Some(title)) = model.get_value(iter, 0).get::<String>() {
+                    let text = tag_entry.get_text().to_string();
+                    let typed = text.len() - last_tag(&text).len();
+                    tag_entry.set_text(&format!("{}{}, ", &text[..typed], title));
+                    tag_entry.set_position(-1);
+                }
+
+                glib::signal::Inhibit(true)
+            })
+        );
+
+        tag_entry.set_completion(Some(&completion));
+        sidebar.set_tags(&[], |_| 0);
+        sidebar
+    }
+
+    /// Call `callback` with the selected view.
+    pub fn connect_selected<F: Fn(View) + 'static>(&self, callback: F) {
+        self.view.get_selection().connect_changed(move |selection| {
+            if let Some((model, iter)) = selection.get_selected() {
+                callback(view_at(&model, &iter));
+            }
+        });
+    }
+
+    pub fn selected(&self) -> View {
+        self.view
+            .get_selection()
+            .get_selected()
+            .map_or(View::All, |(model, iter)| view_at(&model, &iter))
+    }
+
+    pub fn select(&self, view: View) {
+        let selection = self.view.get_selection();
+
+        self.store.foreach(|model, _, iter| {
+            let found = view_at(model, iter) == view;
+
+            if found {
+                selection.select_iter(iter);
+            }
+
+            found
+        });
+    }
+
+    /// Show `tags`, nested below their parents. `count` returns the number of notes of a tag.
+    /// The selection is kept if the selected tag still exists.
+    pub fn set_tags<F: Fn(&Tag) -> usize>(&self, tags: &[&Tag], count: F) {
+        let selected = self.selected();
+        let known = tags.iter().map(|tag| tag.uuid).collect::<HashSet<_>>();
+        let mut children: HashMap<Option<Uuid>, Vec<&Tag>> = HashMap::new();
+
+        for tag in tags {
+            let parent = tag.parent().filter(|parent| known.contains(parent) && *parent != tag.uuid);
+            children.entry(parent).or_default().push(tag);
+        }
+
+        self.store.clear();
+        self.titles.clear();
+
+        let all = self.store.insert_with_values(None, None,
+            &[COLUMN_TITLE, COLUMN_COUNT, COLUMN_VIEW], &[&"All notes", &"", &ALL_NOTES]);
+
+        let mut inserted = HashSet::new();
+        self.insert_children(None, None, &children, &count, &mut inserted);
+
+        // Tags whose parents form a cycle are not reachable from the top, show them there.
+        for tag in tags {
+            if !inserted.insert(tag.uuid) {
+                continue;
+            }
+
+            let iter = self.insert_tag(None, tag, &count);
+            self.insert_children(Some(tag.uuid), Some(&iter), &children, &count, &mut inserted);
+        }
+
+        for (title, view) in &[("Archived", ARCHIVED), ("Trash", TRASH)] {
+            self.store.insert_with_values(None, None,
+                &[COLUMN_TITLE, COLUMN_COUNT, COLUMN_VIEW], &[title, &"", view]);
+        }
+
+        for tag in tags {
+            self.titles.insert_with_values(None, &[0], &[&tag.title]);
+        }
+
+        self.view.expand_all();
+
+        let mut selected_iter = None;
+
+        self.store.foreach(|model, _, iter| {
+            if view_at(model, iter) == selected {
+                selected_iter = Some(iter.clone());
+            }
+
+            selected_iter.is_some()
+        });
+
+        self.view.get_selection().select_iter(selected_iter.as_ref().unwrap_or(&all));
+    }
+
+    fn insert_tag<F: Fn(&Tag) -> usize>(&self, parent: Option<&gtk::TreeIter>, tag: &Tag, count: &F) -> gtk::TreeIter {
+        self.store.insert_with_values(parent, None,
+            &[COLUMN_TITLE, COLUMN_COUNT, COLUMN_VIEW],
+            &[&tag.title, &count(tag).to_string(), &tag.uuid.to_hyphenated().to_string()])
+    }
+
+    fn insert_children<F: Fn(&Tag) -> usize>(&self, parent: Option<Uuid>, iter: Option<&gtk::TreeIter>,
+        children: &HashMap<Option<Uuid>, Vec<&Tag>>, count: &F, inserted: &mut HashSet<Uuid>) {
+        for tag in children.get(&parent).into_iter().flatten() {
+            if !inserted.insert(tag.uuid) {
+                continue;
+            }
+
+            let child = self.insert_tag(iter, tag, count);
+            self.insert_children(Some(tag.uuid), Some(&child), children, count, inserted);
+        }
+    }
+}
+
+fn view_at(model: &gtk::TreeModel, iter: &gtk::TreeIter) -> View {
+    let value = model.get_value(iter, COLUMN_VIEW as i32).get::<String>().ok().flatten().unwrap_or_default();
+
+    match value.as_str() {
+        value if value == ARCHIVED => View::Archived,
+        value if value == TRASH => View::Trash,
+        value => value.parse().map_or(View::All, View::Tag),
+    }
+}
@@ -6,6 +6,14 @@ macro_rules! action {
     };
 }
 
+macro_rules! toggle_action {
+    ($actions_group:expr, $name:expr, $callback:expr) => {
+        let simple_action = gio::SimpleAction::new_stateful($name, None, &false.to_variant());
+        simple_action.connect_change_state($callback);
+        $actions_group.add_action(&simple_action);
+    };
+}
+
 macro_rules! get_widget {
     ($builder:expr, $widget_type:ty, $name:expr) => {{
         $builder.get_object::<$widget_type>($name).unwrap()
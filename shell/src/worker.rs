@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use crate::consts::APP_DOMAIN;
+use standardfile::{remote, remote::{MfaCode, Session, SyncResult}, Credentials, Envelope};
+use std::sync::mpsc;
+use std::thread;
+
+/// Requests processed on the worker thread in the order they were sent.
+enum Request {
+    Sync(Vec<Envelope>, Option<String>),
+    ChangePassword(Credentials),
+}
+
+/// How the worker authenticates with the remote.
+enum Auth {
+    Register,
+    SignIn(Option<MfaCode>),
+    Resume(Session),
+}
+
+/// Results of the worker. Credentials are the ones the server has for the account, these may
+/// differ from the ones we signed in with.
+pub enum Response {
+    Registered(Result<(Credentials, Session)>),
+    SignedIn(Result<(Credentials, Session)>),
+    /// The session has been refreshed and must be stored to be resumed later.
+    SessionChanged(Session),
+    /// Number of items retrieved so far by a running sync.
+    Progress(usize),
+    Synced(Result<SyncResult>),
+    PasswordChanged(Result<Credentials>),
+}
+
+/// Talks to a remote on a separate thread, so the main loop never blocks on the network.
+pub struct Worker {
+    host: String,
+    credentials: Credentials,
+    sender: mpsc::Sender<Request>,
+}
+
+impl Worker {
+    /// Register a new account on a new thread. `notify` is called on the worker thread with
+    /// every response.
+    pub fn register<F>(host: &str, credentials: Credentials, notify: F) -> Self
+    where
+        F: Fn(Response) + Send + 'static,
+    {
+        Worker::spawn(host, credentials, Auth::Register, notify)
+    }
+
+    /// Sign in on a new thread, answering a two-factor challenge with `mfa` if given. `notify`
+    /// is called on the worker thread with every response.
+    pub fn sign_in<F>(host: &str, credentials: Credentials, mfa: Option<MfaCode>, notify: F) -> Self
+    where
+        F: Fn(Response) + Send + 'static,
+    {
+        Worker::spawn(host, credentials, Auth::SignIn(mfa), notify)
+    }
+
+    /// Continue a session of a previous run on a new thread. If the session has expired, sign
+    /// in with `credentials` instead.
+    pub fn resume<F>(host: &str, credentials: Credentials, session: Session, notify: F) -> Self
+    where
+        F: Fn(Response) + Send + 'static,
+    {
+        let auth = if session.is_expired() { Auth::SignIn(None) } else { Auth::Resume(session) };
+        Worker::spawn(host, credentials, auth, notify)
+    }
+
+    fn spawn<F>(host: &str, credentials: Credentials, auth: Auth, notify: F) -> Self
+    where
+        F: Fn(Response) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let remote_host = host.to_string();
+        let worker_credentials = credentials.clone();
+
+        thread::spawn(move || {
+            let mut client = match auth {
+                Auth::Register => match remote::Client::new_register(&remote_host, credentials) {
+                    Ok(client) => {
+                        notify(Response::Registered(Ok((client.credentials.clone(), client.session().clone()))));
+                        client
+                    }
+                    Err(err) => return notify(Response::Registered(Err(err))),
+                },
+                Auth::SignIn(mfa) => match remote::Client::new_sign_in_with_mfa(&remote_host, &credentials, mfa.as_ref()) {
+                    Ok(client) => {
+                        notify(Response::SignedIn(Ok((client.credentials.clone(), client.session().clone()))));
+                        client
+                    }
+                    Err(err) => return notify(Response::SignedIn(Err(err))),
+                },
+                Auth::Resume(session) => {
+                    let client = remote::Client::from_session(&remote_host, &credentials, session.clone());
+                    notify(Response::SignedIn(Ok((credentials, session))));
+                    client
+                }
+            };
+
+            let mut session = client.session().clone();
+
+            // The loop ends as soon as the worker is dropped.
+            for request in receiver {
+                match request {
+                    Request::Sync(items, sync_token) => {
+                        g_info!(APP_DOMAIN, "Syncing {} items with {}", items.len(), remote_host);
+                        client.set_sync_token(sync_token);
+                        let result = client.sync_with_progress(items, |count| notify(Response::Progress(count)));
+                        notify(Response::Synced(result));
+                    }
+                    Request::ChangePassword(credentials) => {
+                        g_info!(APP_DOMAIN, "Changing password on {}", remote_host);
+                        let result = client.change_password(&credentials).map(|_| credentials);
+                        notify(Response::PasswordChanged(result));
+                    }
+                }
+
+                if *client.session() != session {
+                    session = client.session().clone();
+                    notify(Response::SessionChanged(session.clone()));
+                }
+            }
+        });
+
+        Self {
+            host: host.to_string(),
+            credentials: worker_credentials,
+            sender,
+        }
+    }
+
+    /// Address of the remote.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Identifier of the account the worker signs in to.
+    pub fn identifier(&self) -> &str {
+        &self.credentials.identifier
+    }
+
+    /// Credentials the remote currently accepts, used to sign in again.
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// Take note of credentials the remote accepted after a password change.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
+    /// Upload `items` and retrieve everything changed since `sync_token`. Fails if the worker
+    /// is gone because signing in failed.
+    pub fn sync(&self, items: Vec<Envelope>, sync_token: Option<String>) -> Result<()> {
+        self.send(Request::Sync(items, sync_token))
+    }
+
+    /// Change the password on the remote to the one in `credentials`.
+    pub fn change_password(&self, credentials: Credentials) -> Result<()> {
+        self.send(Request::ChangePassword(credentials))
+    }
+
+    fn send(&self, request: Request) -> Result<()> {
+        self.sender.send(request).map_err(|_| anyhow!("Not connected to {}", self.host))
+    }
+}